@@ -0,0 +1,352 @@
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+use crate::expr::{IExpr, VExpr, FExpr, Normalization, Unary, Binary, ComplexUnary, FUnary, FBinary};
+
+/// A handle to an integer expression stored in an `ExprArena`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct IExprId(u32);
+
+/// A handle to a pair expression stored in an `ExprArena`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct VExprId(u32);
+
+/// A handle to a float expression stored in an `ExprArena`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct FExprId(u32);
+
+/// The arena counterpart of `IExpr`, with children referred to by handle.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum INode {
+    Lit(i32),
+    Rgb([u8; 3]),
+    PixelX,
+    PixelY,
+    Channel,
+    Scale256(IExprId),
+    UnaryI(Unary, IExprId),
+    BinaryI(Binary, IExprId, IExprId),
+    BinaryV(Binary, VExprId),
+    IfThenElseI(IExprId, IExprId, IExprId),
+    IfThenElseV(IExprId, VExprId),
+    Time,
+    FromF(FExprId),
+    Radius,
+    Angle,
+    Noise(u32, u8, VExprId),
+    Let(IExprId, IExprId),
+    Var(u32),
+    Normalize(Normalization, IExprId),
+    Offset(i8, i8, IExprId),
+    Blur(u8, IExprId),
+    Sobel(IExprId),
+    Iterate(u8, VExprId, VExprId),
+}
+
+/// The arena counterpart of `VExpr`, with children referred to by handle.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum VNode {
+    Pixel,
+    Swap(VExprId),
+    BinaryI(Binary, Binary, IExprId, IExprId),
+    UnaryV(Unary, VExprId),
+    BinaryV(Binary, VExprId, VExprId),
+    IfThenElseI(IExprId, VExprId, VExprId),
+    IfThenElseV(VExprId, VExprId, VExprId),
+    CenteredPixel,
+    Iterand,
+    UnaryC(ComplexUnary, VExprId),
+    ComplexMul(VExprId, VExprId),
+}
+
+/// The arena counterpart of `FExpr`, with children referred to by handle.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum FNode {
+    Lit(f32),
+    FromI(IExprId),
+    UnaryF(FUnary, FExprId),
+    BinaryF(FBinary, FExprId, FExprId),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum Node {
+    I(INode),
+    V(VNode),
+    F(FNode),
+}
+
+/// A flat table of expression nodes.
+///
+/// Nodes are only ever appended, and a node can only refer to nodes that were
+/// added before it, so the table never contains cycles.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(try_from = "RawArena")]
+pub struct ExprArena {
+    nodes: Vec<Node>,
+    /// The handle of every node by its serialized form, if identical nodes
+    /// should be stored only once.
+    #[serde(skip)]
+    interned: Option<HashMap<Vec<u8>, u32>>,
+}
+
+#[derive(Deserialize)]
+struct RawArena {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+pub struct InvalidArena {
+    node: usize,
+}
+
+impl Display for InvalidArena {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "node {} refers to a node that doesn't precede it or has the wrong type", self.node)
+    }
+}
+
+impl TryFrom<RawArena> for ExprArena {
+    type Error = InvalidArena;
+    fn try_from(raw: RawArena) -> Result<Self, InvalidArena> {
+        let nodes = raw.nodes;
+        let is_i = |i: usize, IExprId(id)| (id as usize) < i && matches!(nodes[id as usize], Node::I(_));
+        let is_v = |i: usize, VExprId(id)| (id as usize) < i && matches!(nodes[id as usize], Node::V(_));
+        let is_f = |i: usize, FExprId(id)| (id as usize) < i && matches!(nodes[id as usize], Node::F(_));
+        for (i, node) in nodes.iter().enumerate() {
+            let valid = match *node {
+                Node::I(node) => match node {
+                    INode::Lit(_) | INode::Rgb(_) | INode::PixelX | INode::PixelY | INode::Channel | INode::Time
+                        | INode::Radius | INode::Angle | INode::Var(_) => true,
+                    INode::Scale256(e) | INode::UnaryI(_, e) | INode::Normalize(_, e)
+                        | INode::Offset(_, _, e) | INode::Blur(_, e) | INode::Sobel(e) => is_i(i, e),
+                    INode::BinaryI(_, e_1, e_2) | INode::Let(e_1, e_2) => is_i(i, e_1) && is_i(i, e_2),
+                    INode::BinaryV(_, e) | INode::Noise(_, _, e) => is_v(i, e),
+                    INode::Iterate(_, e_1, e_2) => is_v(i, e_1) && is_v(i, e_2),
+                    INode::IfThenElseI(e_1, e_2, e_3) => is_i(i, e_1) && is_i(i, e_2) && is_i(i, e_3),
+                    INode::IfThenElseV(e_1, e_2) => is_i(i, e_1) && is_v(i, e_2),
+                    INode::FromF(e) => is_f(i, e),
+                }
+                Node::V(node) => match node {
+                    VNode::Pixel | VNode::CenteredPixel | VNode::Iterand => true,
+                    VNode::Swap(e) | VNode::UnaryV(_, e) | VNode::UnaryC(_, e) => is_v(i, e),
+                    VNode::BinaryI(_, _, e_1, e_2) => is_i(i, e_1) && is_i(i, e_2),
+                    VNode::BinaryV(_, e_1, e_2) | VNode::ComplexMul(e_1, e_2) => is_v(i, e_1) && is_v(i, e_2),
+                    VNode::IfThenElseI(e_1, e_2, e_3) => is_i(i, e_1) && is_v(i, e_2) && is_v(i, e_3),
+                    VNode::IfThenElseV(e_1, e_2, e_3) => is_v(i, e_1) && is_v(i, e_2) && is_v(i, e_3),
+                }
+                Node::F(node) => match node {
+                    FNode::Lit(_) => true,
+                    FNode::FromI(e) => is_i(i, e),
+                    FNode::UnaryF(_, e) => is_f(i, e),
+                    FNode::BinaryF(_, e_1, e_2) => is_f(i, e_1) && is_f(i, e_2),
+                }
+            };
+            if !valid {
+                return Err(InvalidArena { node: i });
+            }
+        }
+        Ok(Self { nodes, interned: None })
+    }
+}
+
+impl ExprArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a boxed expression in a new arena.
+    pub fn from_iexpr(expr: &IExpr) -> (Self, IExprId) {
+        let mut arena = Self::new();
+        let root = arena.insert_iexpr(expr);
+        (arena, root)
+    }
+
+    /// Create an arena that stores each distinct node only once, so that
+    /// identical subexpressions are given the same handle.
+    pub fn interning() -> Self {
+        Self { nodes: Vec::new(), interned: Some(HashMap::new()) }
+    }
+
+    fn push(&mut self, node: Node) -> u32 {
+        let next = self.nodes.len() as u32;
+        if let Some(interned) = &mut self.interned {
+            let id = *interned.entry(rmp_serde::to_vec(&node).unwrap()).or_insert(next);
+            if id != next {
+                return id;
+            }
+        }
+        self.nodes.push(node);
+        next
+    }
+
+    pub fn push_i(&mut self, node: INode) -> IExprId {
+        IExprId(self.push(Node::I(node)))
+    }
+
+    pub fn push_v(&mut self, node: VNode) -> VExprId {
+        VExprId(self.push(Node::V(node)))
+    }
+
+    pub fn push_f(&mut self, node: FNode) -> FExprId {
+        FExprId(self.push(Node::F(node)))
+    }
+
+    pub fn inode(&self, IExprId(id): IExprId) -> INode {
+        match self.nodes[id as usize] {
+            Node::I(node) => node,
+            _ => panic!("IExprId refers to a node that isn't an integer expression"),
+        }
+    }
+
+    pub fn vnode(&self, VExprId(id): VExprId) -> VNode {
+        match self.nodes[id as usize] {
+            Node::V(node) => node,
+            _ => panic!("VExprId refers to a node that isn't a pair expression"),
+        }
+    }
+
+    pub fn fnode(&self, FExprId(id): FExprId) -> FNode {
+        match self.nodes[id as usize] {
+            Node::F(node) => node,
+            _ => panic!("FExprId refers to a node that isn't a float expression"),
+        }
+    }
+
+    pub fn insert_iexpr(&mut self, expr: &IExpr) -> IExprId {
+        let node = match expr {
+            IExpr::Lit(n) => INode::Lit(*n),
+            IExpr::Rgb(rgb) => INode::Rgb(*rgb),
+            IExpr::PixelX => INode::PixelX,
+            IExpr::PixelY => INode::PixelY,
+            IExpr::Channel => INode::Channel,
+            IExpr::Time => INode::Time,
+            IExpr::Radius => INode::Radius,
+            IExpr::Angle => INode::Angle,
+            IExpr::Noise(seed, scale, sub_e) => INode::Noise(*seed, *scale, self.insert_vexpr(sub_e)),
+            IExpr::Let(e_1, e_2) => INode::Let(self.insert_iexpr(e_1), self.insert_iexpr(e_2)),
+            IExpr::Var(n) => INode::Var(*n),
+            IExpr::Normalize(kind, sub_e) => INode::Normalize(*kind, self.insert_iexpr(sub_e)),
+            IExpr::Offset(dx, dy, sub_e) => INode::Offset(*dx, *dy, self.insert_iexpr(sub_e)),
+            IExpr::Blur(radius, sub_e) => INode::Blur(*radius, self.insert_iexpr(sub_e)),
+            IExpr::Sobel(sub_e) => INode::Sobel(self.insert_iexpr(sub_e)),
+            IExpr::Iterate(n, e_body, e_init) =>
+                INode::Iterate(*n, self.insert_vexpr(e_body), self.insert_vexpr(e_init)),
+            IExpr::Scale256(sub_e) => INode::Scale256(self.insert_iexpr(sub_e)),
+            IExpr::UnaryI(op, sub_e) => INode::UnaryI(*op, self.insert_iexpr(sub_e)),
+            IExpr::BinaryI(op, e_1, e_2) =>
+                INode::BinaryI(*op, self.insert_iexpr(e_1), self.insert_iexpr(e_2)),
+            IExpr::BinaryV(op, sub_e) => INode::BinaryV(*op, self.insert_vexpr(sub_e)),
+            IExpr::IfThenElseI(e_1, e_2, e_3) => INode::IfThenElseI(
+                self.insert_iexpr(e_1),
+                self.insert_iexpr(e_2),
+                self.insert_iexpr(e_3)),
+            IExpr::IfThenElseV(e_1, e_2) =>
+                INode::IfThenElseV(self.insert_iexpr(e_1), self.insert_vexpr(e_2)),
+            IExpr::FromF(sub_e) => INode::FromF(self.insert_fexpr(sub_e)),
+        };
+        self.push_i(node)
+    }
+
+    pub fn insert_vexpr(&mut self, expr: &VExpr) -> VExprId {
+        let node = match expr {
+            VExpr::Pixel => VNode::Pixel,
+            VExpr::CenteredPixel => VNode::CenteredPixel,
+            VExpr::Iterand => VNode::Iterand,
+            VExpr::Swap(sub_e) => VNode::Swap(self.insert_vexpr(sub_e)),
+            VExpr::BinaryI(op_1, op_2, e_1, e_2) =>
+                VNode::BinaryI(*op_1, *op_2, self.insert_iexpr(e_1), self.insert_iexpr(e_2)),
+            VExpr::UnaryV(op, sub_e) => VNode::UnaryV(*op, self.insert_vexpr(sub_e)),
+            VExpr::BinaryV(op, e_1, e_2) =>
+                VNode::BinaryV(*op, self.insert_vexpr(e_1), self.insert_vexpr(e_2)),
+            VExpr::IfThenElseI(e_1, e_2, e_3) => VNode::IfThenElseI(
+                self.insert_iexpr(e_1),
+                self.insert_vexpr(e_2),
+                self.insert_vexpr(e_3)),
+            VExpr::IfThenElseV(e_1, e_2, e_3) => VNode::IfThenElseV(
+                self.insert_vexpr(e_1),
+                self.insert_vexpr(e_2),
+                self.insert_vexpr(e_3)),
+            VExpr::UnaryC(op, sub_e) => VNode::UnaryC(*op, self.insert_vexpr(sub_e)),
+            VExpr::ComplexMul(e_1, e_2) =>
+                VNode::ComplexMul(self.insert_vexpr(e_1), self.insert_vexpr(e_2)),
+        };
+        self.push_v(node)
+    }
+
+    pub fn insert_fexpr(&mut self, expr: &FExpr) -> FExprId {
+        let node = match expr {
+            FExpr::Lit(n) => FNode::Lit(*n),
+            FExpr::FromI(sub_e) => FNode::FromI(self.insert_iexpr(sub_e)),
+            FExpr::UnaryF(op, sub_e) => FNode::UnaryF(*op, self.insert_fexpr(sub_e)),
+            FExpr::BinaryF(op, e_1, e_2) =>
+                FNode::BinaryF(*op, self.insert_fexpr(e_1), self.insert_fexpr(e_2)),
+        };
+        self.push_f(node)
+    }
+
+    /// Copy the expression rooted at `id` out into a boxed tree.
+    pub fn iexpr(&self, id: IExprId) -> IExpr {
+        let i = |id| Box::new(self.iexpr(id));
+        let v = |id| Box::new(self.vexpr(id));
+        let f = |id| Box::new(self.fexpr(id));
+        match self.inode(id) {
+            INode::Lit(n) => IExpr::Lit(n),
+            INode::Rgb(rgb) => IExpr::Rgb(rgb),
+            INode::PixelX => IExpr::PixelX,
+            INode::PixelY => IExpr::PixelY,
+            INode::Channel => IExpr::Channel,
+            INode::Time => IExpr::Time,
+            INode::Radius => IExpr::Radius,
+            INode::Angle => IExpr::Angle,
+            INode::Noise(seed, scale, sub_e) => IExpr::Noise(seed, scale, v(sub_e)),
+            INode::Let(e_1, e_2) => IExpr::Let(i(e_1), i(e_2)),
+            INode::Var(n) => IExpr::Var(n),
+            INode::Normalize(kind, sub_e) => IExpr::Normalize(kind, i(sub_e)),
+            INode::Offset(dx, dy, sub_e) => IExpr::Offset(dx, dy, i(sub_e)),
+            INode::Blur(radius, sub_e) => IExpr::Blur(radius, i(sub_e)),
+            INode::Sobel(sub_e) => IExpr::Sobel(i(sub_e)),
+            INode::Iterate(n, e_body, e_init) => IExpr::Iterate(n, v(e_body), v(e_init)),
+            INode::Scale256(sub_e) => IExpr::Scale256(i(sub_e)),
+            INode::UnaryI(op, sub_e) => IExpr::UnaryI(op, i(sub_e)),
+            INode::BinaryI(op, e_1, e_2) => IExpr::BinaryI(op, i(e_1), i(e_2)),
+            INode::BinaryV(op, sub_e) => IExpr::BinaryV(op, v(sub_e)),
+            INode::IfThenElseI(e_1, e_2, e_3) => IExpr::IfThenElseI(i(e_1), i(e_2), i(e_3)),
+            INode::IfThenElseV(e_1, e_2) => IExpr::IfThenElseV(i(e_1), v(e_2)),
+            INode::FromF(sub_e) => IExpr::FromF(f(sub_e)),
+        }
+    }
+
+    /// Copy the expression rooted at `id` out into a boxed tree.
+    pub fn vexpr(&self, id: VExprId) -> VExpr {
+        let i = |id| Box::new(self.iexpr(id));
+        let v = |id| Box::new(self.vexpr(id));
+        match self.vnode(id) {
+            VNode::Pixel => VExpr::Pixel,
+            VNode::CenteredPixel => VExpr::CenteredPixel,
+            VNode::Iterand => VExpr::Iterand,
+            VNode::Swap(sub_e) => VExpr::Swap(v(sub_e)),
+            VNode::BinaryI(op_1, op_2, e_1, e_2) => VExpr::BinaryI(op_1, op_2, i(e_1), i(e_2)),
+            VNode::UnaryV(op, sub_e) => VExpr::UnaryV(op, v(sub_e)),
+            VNode::BinaryV(op, e_1, e_2) => VExpr::BinaryV(op, v(e_1), v(e_2)),
+            VNode::IfThenElseI(e_1, e_2, e_3) => VExpr::IfThenElseI(i(e_1), v(e_2), v(e_3)),
+            VNode::IfThenElseV(e_1, e_2, e_3) => VExpr::IfThenElseV(v(e_1), v(e_2), v(e_3)),
+            VNode::UnaryC(op, sub_e) => VExpr::UnaryC(op, v(sub_e)),
+            VNode::ComplexMul(e_1, e_2) => VExpr::ComplexMul(v(e_1), v(e_2)),
+        }
+    }
+
+    /// Copy the expression rooted at `id` out into a boxed tree.
+    pub fn fexpr(&self, id: FExprId) -> FExpr {
+        let i = |id| Box::new(self.iexpr(id));
+        let f = |id| Box::new(self.fexpr(id));
+        match self.fnode(id) {
+            FNode::Lit(n) => FExpr::Lit(n),
+            FNode::FromI(sub_e) => FExpr::FromI(i(sub_e)),
+            FNode::UnaryF(op, sub_e) => FExpr::UnaryF(op, f(sub_e)),
+            FNode::BinaryF(op, e_1, e_2) => FExpr::BinaryF(op, f(e_1), f(e_2)),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use serde::{Serialize, Deserialize};
+
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::expr::IExpr;
+use crate::validate::Limits;
+
+/// An expression along with everything else needed to turn it into an image.
+/// This is what gets serialized into URLs.
+#[derive(Serialize, Deserialize)]
+pub struct Artwork {
+    pub expr: IExpr,
+    #[serde(default)]
+    pub color_mode: ColorMode,
+    /// If this is set, the first channel is mapped through the palette and
+    /// the other channels and the color mode are ignored.
+    #[serde(default)]
+    pub palette: Option<Palette>,
+}
+
+/// Find how deeply the arrays and maps at the start of some MessagePack data
+/// are nested, giving up once it's more than `max`. This doesn't recurse, so
+/// it's safe to use on data that would overflow the stack when decoded.
+/// Malformed data is left for the decoder to reject.
+fn nesting_exceeds(data: &[u8], max: usize) -> bool {
+    let read = |pos: usize, len: usize| -> u64 {
+        data.get(pos..pos + len).map_or(0, |bytes| bytes.iter().fold(0, |n, &byte| n << 8 | byte as u64))
+    };
+    // The number of values still to be read in each array or map that's
+    // open, outermost first.
+    let mut remaining = vec![1u64];
+    let mut pos = 0;
+    while let Some(left) = remaining.last_mut() {
+        if *left == 0 {
+            remaining.pop();
+            continue;
+        }
+        if pos >= data.len() {
+            return false;
+        }
+        *left -= 1;
+        let marker = data[pos];
+        pos += 1;
+        // The number of bytes after the marker, and the number of values
+        // inside the array or map that it starts.
+        let (skip, values) = match marker {
+            0x00..=0x7f | 0xc0..=0xc3 | 0xe0..=0xff => (0, 0),
+            0x80..=0x8f => (0, 2 * (marker & 0x0f) as u64),
+            0x90..=0x9f => (0, (marker & 0x0f) as u64),
+            0xa0..=0xbf => ((marker & 0x1f) as u64, 0),
+            0xc4 | 0xd9 => (1 + read(pos, 1), 0),
+            0xc5 | 0xda => (2 + read(pos, 2), 0),
+            0xc6 | 0xdb => (4 + read(pos, 4), 0),
+            0xc7 => (2 + read(pos, 1), 0),
+            0xc8 => (3 + read(pos, 2), 0),
+            0xc9 => (5 + read(pos, 4), 0),
+            0xcc | 0xd0 => (1, 0),
+            0xcd | 0xd1 => (2, 0),
+            0xca | 0xce | 0xd2 => (4, 0),
+            0xcb | 0xcf | 0xd3 => (8, 0),
+            0xd4..=0xd8 => (1 + (1 << (marker - 0xd4)), 0),
+            0xdc => (2, read(pos, 2)),
+            0xdd => (4, read(pos, 4)),
+            0xde => (2, 2 * read(pos, 2)),
+            0xdf => (4, 2 * read(pos, 4)),
+        };
+        pos = pos.saturating_add(skip as usize);
+        if values > 0 {
+            remaining.push(values);
+            if remaining.len() - 1 > max {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+impl Artwork {
+    /// Decode an artwork, also accepting a bare expression as serialized
+    /// before artworks existed. Data nested too deeply to hold an expression
+    /// within the default depth limit is rejected before it's decoded, since
+    /// the decoder recurses once for each level.
+    pub fn decode(serialized: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        // Each node of an expression is at most two levels, and the artwork
+        // and the node's operator take a few more.
+        if nesting_exceeds(serialized, 2 * Limits::default().max_depth + 8) {
+            return Err(rmp_serde::decode::Error::DepthLimitExceeded);
+        }
+        rmp_serde::from_slice(serialized).or_else(|e| {
+            rmp_serde::from_slice(serialized)
+                .map(|expr| Self { expr, color_mode: ColorMode::default(), palette: None })
+                .map_err(|_| e)
+        })
+    }
+}
+
+/// How the three channels of an expression's output are read as a color.
+/// Each channel is between 0 and 255.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum ColorMode {
+    #[default]
+    Rgb,
+    /// Hue, saturation and value. A hue of 256 would be a full turn.
+    Hsv,
+    /// Hue, saturation and lightness.
+    Hsl,
+    /// Lightness and the a and b axes of the OKLab color space, where 128 is
+    /// roughly neutral.
+    Oklab,
+}
+
+impl Display for ColorMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ColorMode::Rgb => "rgb",
+            ColorMode::Hsv => "hsv",
+            ColorMode::Hsl => "hsl",
+            ColorMode::Oklab => "oklab",
+        })
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "rgb" => Ok(ColorMode::Rgb),
+            "hsv" => Ok(ColorMode::Hsv),
+            "hsl" => Ok(ColorMode::Hsl),
+            "oklab" => Ok(ColorMode::Oklab),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A gradient mapping a single channel between 0 and 255 to colors.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Palette {
+    Viridis,
+    Magma,
+    Plasma,
+    /// Colors at positions between 0 and 255, in increasing order of position.
+    /// Values between two stops blend their colors, and values beyond the
+    /// first or last stop take its color.
+    Stops(Vec<(u8, [u8; 3])>),
+}
+
+impl Display for Palette {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Palette::Viridis => f.write_str("viridis"),
+            Palette::Magma => f.write_str("magma"),
+            Palette::Plasma => f.write_str("plasma"),
+            Palette::Stops(stops) => {
+                for (i, (position, [r, g, b])) in stops.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{:02x}{:02x}{:02x}", position, r, g, b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parses the name of a built-in palette, or a list of stops written as
+/// `position:rrggbb` and separated by commas.
+impl FromStr for Palette {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "viridis" => return Ok(Palette::Viridis),
+            "magma" => return Ok(Palette::Magma),
+            "plasma" => return Ok(Palette::Plasma),
+            _ => {}
+        }
+        let stops = s.split(',')
+            .map(|stop| {
+                let (position, color) = stop.split_once(':').ok_or(())?;
+                let color = hex::decode(color).map_err(|_| ())?;
+                Ok((position.parse().map_err(|_| ())?, color.try_into().map_err(|_| ())?))
+            })
+            .collect::<Result<Vec<(u8, [u8; 3])>, ()>>()?;
+        if stops.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(());
+        }
+        Ok(Palette::Stops(stops))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Unary;
+
+    #[test]
+    fn expressions_within_the_depth_limit_decode() {
+        let mut expr = IExpr::Rgb([1, 2, 3]);
+        for _ in 0..Limits::default().max_depth {
+            expr = IExpr::UnaryI(Unary::DivBy(3), Box::new(expr));
+        }
+        let palette = Some(Palette::Stops(vec![(0, [1, 2, 3]), (255, [4, 5, 6])]));
+        let artwork = Artwork { expr, color_mode: ColorMode::Hsv, palette };
+        let decoded = Artwork::decode(&rmp_serde::to_vec(&artwork).unwrap()).unwrap();
+        assert!(decoded.expr == artwork.expr);
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected_before_decoding() {
+        // `Scale256(PixelX)` is `{5: {2: nil}}`, so repeating its start nests
+        // it as deeply as we like.
+        let mut serialized = vec![0x93];
+        serialized.extend([0x81, 0x05].repeat(100_000));
+        serialized.extend(&[0x81, 0x02, 0xc0, 0x81, 0x00, 0xc0, 0xc0]);
+        assert!(matches!(Artwork::decode(&serialized), Err(rmp_serde::decode::Error::DepthLimitExceeded)));
+        assert!(matches!(Artwork::decode(&serialized[1..]), Err(rmp_serde::decode::Error::DepthLimitExceeded)));
+    }
+}
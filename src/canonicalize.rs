@@ -0,0 +1,174 @@
+use serde::Serialize;
+
+use std::cmp::Ordering;
+
+use crate::expr::{IExpr, VExpr, FExpr, Unary, ComplexUnary};
+
+/// Put two operands in an order that's arbitrary but the same every time:
+/// the order of their serialized forms.
+fn sorted<T: Serialize>(a: T, b: T) -> (T, T) {
+    let order = rmp_serde::to_vec(&a).unwrap().cmp(&rmp_serde::to_vec(&b).unwrap());
+    match order {
+        Ordering::Greater => (b, a),
+        _ => (a, b),
+    }
+}
+
+/// Like `sorted`, if `commutative` is true.
+fn sorted_if<T: Serialize>(commutative: bool, a: T, b: T) -> (Box<T>, Box<T>) {
+    let (a, b) = if commutative { sorted(a, b) } else { (a, b) };
+    (Box::new(a), Box::new(b))
+}
+
+impl IExpr {
+    /// Rewrite the expression so that expressions that differ only in the
+    /// order of operands of commutative operators, or in pairs of operators
+    /// that undo each other, are rewritten the same way. Unlike `simplify`,
+    /// this never changes the rest of the expression's structure.
+    pub fn canonicalize(self) -> IExpr {
+        use IExpr::*;
+        match self {
+            Scale256(sub_e) => Scale256(Box::new(sub_e.canonicalize())),
+            UnaryI(op, sub_e) => match (op, sub_e.canonicalize()) {
+                (Unary::Neg, UnaryI(Unary::Neg, sub_e)) | (Unary::Not, UnaryI(Unary::Not, sub_e)) => *sub_e,
+                (op, sub_e) => UnaryI(op, Box::new(sub_e)),
+            },
+            BinaryI(op, e_1, e_2) => {
+                let (e_1, e_2) = sorted_if(op.is_commutative(), e_1.canonicalize(), e_2.canonicalize());
+                BinaryI(op, e_1, e_2)
+            }
+            // Swapping the pair is the same as swapping the operands.
+            BinaryV(op, sub_e) => match sub_e.canonicalize() {
+                VExpr::Swap(sub_e) if op.is_commutative() => BinaryV(op, sub_e),
+                sub_e => BinaryV(op, Box::new(sub_e)),
+            },
+            IfThenElseI(e_cond, e_then, e_else) => IfThenElseI(
+                Box::new(e_cond.canonicalize()),
+                Box::new(e_then.canonicalize()),
+                Box::new(e_else.canonicalize())),
+            IfThenElseV(e_cond, e_case) =>
+                IfThenElseV(Box::new(e_cond.canonicalize()), Box::new(e_case.canonicalize())),
+            FromF(sub_e) => FromF(Box::new(sub_e.canonicalize())),
+            Noise(seed, scale, sub_e) => Noise(seed, scale, Box::new(sub_e.canonicalize())),
+            Let(e_value, e_body) => Let(Box::new(e_value.canonicalize()), Box::new(e_body.canonicalize())),
+            Normalize(kind, sub_e) => Normalize(kind, Box::new(sub_e.canonicalize())),
+            Offset(dx, dy, sub_e) => Offset(dx, dy, Box::new(sub_e.canonicalize())),
+            Blur(radius, sub_e) => Blur(radius, Box::new(sub_e.canonicalize())),
+            Sobel(sub_e) => Sobel(Box::new(sub_e.canonicalize())),
+            Iterate(n, e_body, e_init) =>
+                Iterate(n, Box::new(e_body.canonicalize()), Box::new(e_init.canonicalize())),
+            leaf => leaf,
+        }
+    }
+
+    /// A hash of the canonical form of the expression, which can be used to
+    /// recognize the same formula written differently. Unlike `Hash`, this
+    /// stays the same between runs and builds.
+    pub fn fingerprint(&self) -> u64 {
+        // 64-bit FNV-1a.
+        rmp_serde::to_vec(&self.clone().canonicalize()).unwrap()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+}
+
+impl VExpr {
+    pub fn canonicalize(self) -> VExpr {
+        use VExpr::*;
+        match self {
+            Swap(sub_e) => match sub_e.canonicalize() {
+                Swap(sub_e) => *sub_e,
+                sub_e => Swap(Box::new(sub_e)),
+            },
+            BinaryI(op_1, op_2, e_1, e_2) => {
+                let commutative = op_1.is_commutative() && op_2.is_commutative();
+                let (e_1, e_2) = sorted_if(commutative, e_1.canonicalize(), e_2.canonicalize());
+                BinaryI(op_1, op_2, e_1, e_2)
+            }
+            UnaryV(op, sub_e) => match (op, sub_e.canonicalize()) {
+                (Unary::Neg, UnaryV(Unary::Neg, sub_e)) | (Unary::Not, UnaryV(Unary::Not, sub_e)) => *sub_e,
+                (op, sub_e) => UnaryV(op, Box::new(sub_e)),
+            },
+            BinaryV(op, e_1, e_2) => {
+                let (e_1, e_2) = sorted_if(op.is_commutative(), e_1.canonicalize(), e_2.canonicalize());
+                BinaryV(op, e_1, e_2)
+            }
+            IfThenElseI(e_cond, e_then, e_else) => IfThenElseI(
+                Box::new(e_cond.canonicalize()),
+                Box::new(e_then.canonicalize()),
+                Box::new(e_else.canonicalize())),
+            IfThenElseV(e_cond, e_then, e_else) => IfThenElseV(
+                Box::new(e_cond.canonicalize()),
+                Box::new(e_then.canonicalize()),
+                Box::new(e_else.canonicalize())),
+            UnaryC(op, sub_e) => match (op, sub_e.canonicalize()) {
+                (ComplexUnary::Conj, UnaryC(ComplexUnary::Conj, sub_e)) => *sub_e,
+                (op, sub_e) => UnaryC(op, Box::new(sub_e)),
+            },
+            ComplexMul(e_1, e_2) => {
+                let (e_1, e_2) = sorted_if(true, e_1.canonicalize(), e_2.canonicalize());
+                ComplexMul(e_1, e_2)
+            }
+            leaf => leaf,
+        }
+    }
+}
+
+impl FExpr {
+    pub fn canonicalize(self) -> FExpr {
+        use FExpr::*;
+        match self {
+            FromI(sub_e) => FromI(Box::new(sub_e.canonicalize())),
+            UnaryF(op, sub_e) => UnaryF(op, Box::new(sub_e.canonicalize())),
+            BinaryF(op, e_1, e_2) => {
+                let (e_1, e_2) = sorted_if(op.is_commutative(), e_1.canonicalize(), e_2.canonicalize());
+                BinaryF(op, e_1, e_2)
+            }
+            leaf => leaf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::expr::IExpr;
+    use crate::gen_expr::Parameters;
+    use crate::gen_png::assert_same_pixels;
+    use crate::validate::Limits;
+
+    #[test]
+    fn canonicalizing_keeps_known_cases_the_same() {
+        let cases = [
+            "(iterate 8 [+ [c* z [swap z]] [/4 xy]] [/4 xy])",
+            "(iterate 8 [+ [c* [swap z] z] [/4 xy]] [/4 xy])",
+            "(& (+ (* y 3) x) 255)",
+            "(& (neg (neg (^ x y))) 255)",
+            "(& (int (*. 2.0 (float x))) 255)",
+            "(& (let ((a (* x y))) (- (+ a y) x)) 255)",
+        ];
+        for case in &cases {
+            let e = case.parse::<IExpr>().unwrap();
+            assert!(e.validate(&Limits::default()).is_ok(), "`{}` is invalid", case);
+            assert_same_pixels(&e, &e.clone().canonicalize());
+        }
+        // The operands of a complex product are put in order.
+        let (a, b) = (cases[0].parse::<IExpr>().unwrap(), cases[1].parse::<IExpr>().unwrap());
+        assert!(a != b && a.canonicalize() == b.canonicalize());
+    }
+
+    #[test]
+    fn canonicalizing_keeps_generated_expressions_the_same() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let params = Parameters::default();
+        for _ in 0..200 {
+            let e = params.gen_expr(&mut rng, 6, 2).simplify().share_common_subexpressions();
+            e.validate(&Limits::default()).unwrap();
+            let canonical = e.clone().canonicalize();
+            assert_same_pixels(&e, &canonical);
+            assert!(canonical.clone().canonicalize() == canonical, "canonicalizing `{}` again changes it", canonical);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::str::FromStr;
+
+use crate::expr::{IExpr, VExpr, FExpr, Binary, ComplexUnary};
+
+/// The inputs that the result of an expression can depend on. An input is
+/// only left out if the result is certainly the same whatever its value, so
+/// leaving out `channel` means that every channel is equal and the image is
+/// grayscale.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Dependencies {
+    pub x: bool,
+    pub y: bool,
+    pub time: bool,
+    pub channel: bool,
+}
+
+/// Dependencies for both elements of a pair.
+type PairDependencies = (Dependencies, Dependencies);
+
+impl Dependencies {
+    const NONE: Self = Self { x: false, y: false, time: false, channel: false };
+    const ALL: Self = Self { x: true, y: true, time: true, channel: true };
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            x: self.x || other.x,
+            y: self.y || other.y,
+            time: self.time || other.time,
+            channel: self.channel || other.channel,
+        }
+    }
+    /// Whether both include any input.
+    fn overlaps(self, other: Self) -> bool {
+        self.x && other.x || self.y && other.y || self.time && other.time || self.channel && other.channel
+    }
+    /// Whether every pixel of every frame is certainly the same color.
+    pub fn is_flat(self) -> bool {
+        !(self.x || self.y || self.time)
+    }
+    /// The flag for the input with the given name.
+    fn input_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "x" => Some(&mut self.x),
+            "y" => Some(&mut self.y),
+            "time" => Some(&mut self.time),
+            "channel" => Some(&mut self.channel),
+            _ => None,
+        }
+    }
+}
+
+/// Whether applying `op` to an expression and itself always gives the same
+/// value.
+fn is_constant_on_itself(op: Binary) -> bool {
+    use Binary::*;
+    matches!(op, Sub | BitXor | Lt | Eq | Mod)
+}
+
+/// The dependencies of applying `op` to two expressions, given whether
+/// they're the same expression.
+fn binary(op: Binary, same: bool, a: Dependencies, b: Dependencies) -> Dependencies {
+    if same && is_constant_on_itself(op) {
+        Dependencies::NONE
+    } else {
+        a.union(b)
+    }
+}
+
+struct Analyzer {
+    /// The dependencies of the variables in scope, innermost last.
+    vars: Vec<Dependencies>,
+    /// The dependencies of the pairs that the enclosing loop bodies are
+    /// applied to, innermost last.
+    iterands: Vec<PairDependencies>,
+}
+
+impl Analyzer {
+    fn iexpr(&mut self, e: &IExpr) -> Dependencies {
+        use IExpr::*;
+        match e {
+            Lit(_) => Dependencies::NONE,
+            Rgb([r, g, b]) => Dependencies { channel: r != g || g != b, ..Dependencies::NONE },
+            PixelX => Dependencies { x: true, ..Dependencies::NONE },
+            PixelY => Dependencies { y: true, ..Dependencies::NONE },
+            Time => Dependencies { time: true, ..Dependencies::NONE },
+            Channel => Dependencies { channel: true, ..Dependencies::NONE },
+            Radius | Angle => Dependencies { x: true, y: true, ..Dependencies::NONE },
+            Var(n) => match self.vars.len().checked_sub(*n as usize + 1) {
+                Some(i) => self.vars[i],
+                None => Dependencies::ALL,
+            },
+            // Each channel is normalized on its own, and neighboring pixels
+            // of an image that doesn't vary along an axis are the same along
+            // that axis.
+            Scale256(sub_e) | UnaryI(_, sub_e) | Normalize(_, sub_e) | Offset(_, _, sub_e)
+                | Blur(_, sub_e) | Sobel(sub_e) => self.iexpr(sub_e),
+            BinaryI(op, e_1, e_2) => {
+                let (a, b) = (self.iexpr(e_1), self.iexpr(e_2));
+                binary(*op, e_1 == e_2, a, b)
+            }
+            BinaryV(_, sub_e) | Noise(_, _, sub_e) => {
+                let (a, b) = self.vexpr(sub_e);
+                a.union(b)
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                let (cond, then, else_) = (self.iexpr(e_cond), self.iexpr(e_then), self.iexpr(e_else));
+                if e_then == e_else { then } else { cond.union(then).union(else_) }
+            }
+            IfThenElseV(e_cond, e_case) => {
+                let cond = self.iexpr(e_cond);
+                let (a, b) = self.vexpr(e_case);
+                cond.union(a).union(b)
+            }
+            FromF(sub_e) => self.fexpr(sub_e),
+            Let(e_value, e_body) => {
+                let value = self.iexpr(e_value);
+                self.vars.push(value);
+                let body = self.iexpr(e_body);
+                self.vars.pop();
+                body
+            }
+            Iterate(_, e_body, e_init) => {
+                // Each iteration feeds the body's result back into it, so
+                // keep adding the body's dependencies to the pair until they
+                // stop changing.
+                let mut pair = self.vexpr(e_init);
+                loop {
+                    self.iterands.push(pair);
+                    let (a, b) = self.vexpr(e_body);
+                    self.iterands.pop();
+                    let next = (pair.0.union(a), pair.1.union(b));
+                    if next == pair {
+                        break;
+                    }
+                    pair = next;
+                }
+                pair.0.union(pair.1)
+            }
+        }
+    }
+    fn vexpr(&mut self, e: &VExpr) -> PairDependencies {
+        use VExpr::*;
+        match e {
+            Pixel | CenteredPixel => (
+                Dependencies { x: true, ..Dependencies::NONE },
+                Dependencies { y: true, ..Dependencies::NONE },
+            ),
+            Iterand => self.iterands.last().copied().unwrap_or((Dependencies::ALL, Dependencies::ALL)),
+            Swap(sub_e) => {
+                let (a, b) = self.vexpr(sub_e);
+                (b, a)
+            }
+            BinaryI(op_1, op_2, e_1, e_2) => {
+                let (a, b) = (self.iexpr(e_1), self.iexpr(e_2));
+                let same = e_1 == e_2;
+                (binary(*op_1, same, a, b), binary(*op_2, same, a, b))
+            }
+            UnaryV(_, sub_e) | UnaryC(ComplexUnary::Conj, sub_e) => self.vexpr(sub_e),
+            BinaryV(op, e_1, e_2) => {
+                let ((a_1, a_2), (b_1, b_2)) = (self.vexpr(e_1), self.vexpr(e_2));
+                let same = e_1 == e_2;
+                (binary(*op, same, a_1, b_1), binary(*op, same, a_2, b_2))
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                let (cond, then, else_) = (self.iexpr(e_cond), self.vexpr(e_then), self.vexpr(e_else));
+                if e_then == e_else {
+                    then
+                } else {
+                    (cond.union(then.0).union(else_.0), cond.union(then.1).union(else_.1))
+                }
+            }
+            IfThenElseV(e_cond, e_then, e_else) => {
+                let (cond, then, else_) = (self.vexpr(e_cond), self.vexpr(e_then), self.vexpr(e_else));
+                if e_then == e_else {
+                    then
+                } else {
+                    (cond.0.union(then.0).union(else_.0), cond.1.union(then.1).union(else_.1))
+                }
+            }
+            UnaryC(ComplexUnary::Square, sub_e) => {
+                let (a, b) = self.vexpr(sub_e);
+                (a.union(b), a.union(b))
+            }
+            // The imaginary part is always 0.
+            UnaryC(ComplexUnary::SquaredMagnitude, sub_e) => {
+                let (a, b) = self.vexpr(sub_e);
+                (a.union(b), Dependencies::NONE)
+            }
+            ComplexMul(e_1, e_2) => {
+                let ((a_1, a_2), (b_1, b_2)) = (self.vexpr(e_1), self.vexpr(e_2));
+                let all = a_1.union(a_2).union(b_1).union(b_2);
+                (all, all)
+            }
+        }
+    }
+    fn fexpr(&mut self, e: &FExpr) -> Dependencies {
+        use FExpr::*;
+        match e {
+            Lit(_) => Dependencies::NONE,
+            FromI(sub_e) => self.iexpr(sub_e),
+            UnaryF(_, sub_e) => self.fexpr(sub_e),
+            BinaryF(_, e_1, e_2) => {
+                let (a, b) = (self.fexpr(e_1), self.fexpr(e_2));
+                a.union(b)
+            }
+        }
+    }
+}
+
+impl IExpr {
+    /// Find which inputs the result of the expression can depend on without
+    /// evaluating it. This is conservative: an input that can't be ruled out
+    /// counts as a dependency.
+    pub fn dependencies(&self) -> Dependencies {
+        Analyzer { vars: Vec::new(), iterands: Vec::new() }.iexpr(self)
+    }
+}
+
+/// Inputs that a generated image must and mustn't depend on, written as a
+/// comma-separated list of `x`, `y`, `time` and `channel`, where a `!` in
+/// front of an input forbids it. For example, `x,y,!channel` asks for
+/// grayscale images that vary along both axes.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Requirements {
+    pub required: Dependencies,
+    pub forbidden: Dependencies,
+}
+
+impl Requirements {
+    /// Whether an expression with the given dependencies meets the
+    /// requirements.
+    pub fn accepts(&self, dependencies: Dependencies) -> bool {
+        dependencies.union(self.required) == dependencies && !dependencies.overlaps(self.forbidden)
+    }
+}
+
+impl FromStr for Requirements {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut requirements = Self::default();
+        for input in s.split(',').map(str::trim).filter(|input| !input.is_empty()) {
+            let flag = match input.strip_prefix('!') {
+                Some(input) => requirements.forbidden.input_mut(input),
+                None => requirements.required.input_mut(input),
+            };
+            *flag.ok_or(())? = true;
+        }
+        Ok(requirements)
+    }
+}
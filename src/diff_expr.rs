@@ -0,0 +1,418 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::arena::{ExprArena, IExprId, VExprId, FExprId, INode, VNode, FNode};
+use crate::expr::IExpr;
+use crate::path::{Path, write_path};
+use crate::utils;
+
+/// A handle to a node of any type.
+#[derive(Clone, Copy, PartialEq)]
+enum NodeId {
+    I(IExprId),
+    V(VExprId),
+    F(FExprId),
+}
+
+impl NodeId {
+    fn same_type(self, other: NodeId) -> bool {
+        matches!((self, other), (NodeId::I(_), NodeId::I(_)) | (NodeId::V(_), NodeId::V(_)) | (NodeId::F(_), NodeId::F(_)))
+    }
+}
+
+/// How a node is displayed: `parts[0]`, the first subexpression, `parts[1]`,
+/// and so on, ending with `parts[children.len()]`.
+struct Shape {
+    parts: Vec<String>,
+    /// Each subexpression along with the number of `Let`s around it.
+    children: Vec<(NodeId, usize)>,
+}
+
+impl Shape {
+    fn leaf(text: String) -> Self {
+        Self { parts: vec![text], children: Vec::new() }
+    }
+    /// A node shown as the head and subexpressions separated by spaces,
+    /// between `open` and `close`.
+    fn list(open: char, head: String, children: Vec<NodeId>, close: char, lets: usize) -> Self {
+        let mut parts = vec![format!("{}{} ", open, head)];
+        parts.extend((1..children.len()).map(|_| " ".to_owned()));
+        parts.push(close.to_string());
+        Self { parts, children: children.into_iter().map(|id| (id, lets)).collect() }
+    }
+    /// Whether two nodes differ at most in their operators and parameters.
+    fn matches(&self, other: &Shape) -> bool {
+        // Only the text before the first subexpression and after the last one
+        // can differ, which leaves the separators in between.
+        self.children.len() == other.children.len()
+            && self.children.iter().zip(&other.children).all(|(&(a, _), &(b, _))| a.same_type(b))
+            && self.parts[..self.children.len()].iter().skip(1).eq(other.parts[..other.children.len()].iter().skip(1))
+    }
+}
+
+fn shape(arena: &ExprArena, id: NodeId, lets: usize) -> Shape {
+    use NodeId::{I, V, F};
+    let paren = |head: String, children| Shape::list('(', head, children, ')', lets);
+    let bracket = |head: String, children| Shape::list('[', head, children, ']', lets);
+    match id {
+        I(id) => match arena.inode(id) {
+            INode::Let(e_value, e_body) => Shape {
+                parts: vec![format!("(let ((v{} ", lets), ")) ".to_owned(), ")".to_owned()],
+                children: vec![(I(e_value), lets), (I(e_body), lets + 1)],
+            },
+            INode::Lit(_) | INode::Rgb(_) | INode::PixelX | INode::PixelY | INode::Channel | INode::Time
+                | INode::Radius | INode::Angle | INode::Var(_) =>
+                Shape::leaf(arena.display_iexpr_within(id, lets).to_string()),
+            INode::Noise(seed, scale, sub_e) => paren(format!("noise {} {}", seed, scale), vec![V(sub_e)]),
+            INode::Normalize(kind, sub_e) => paren(kind.to_string(), vec![I(sub_e)]),
+            INode::Offset(dx, dy, sub_e) => paren(format!("offset {} {}", dx, dy), vec![I(sub_e)]),
+            INode::Blur(radius, sub_e) => paren(format!("blur {}", radius), vec![I(sub_e)]),
+            INode::Sobel(sub_e) => paren("sobel".to_owned(), vec![I(sub_e)]),
+            INode::Iterate(n, e_body, e_init) => paren(format!("iterate {}", n), vec![V(e_body), V(e_init)]),
+            INode::Scale256(sub_e) => paren("scale-256".to_owned(), vec![I(sub_e)]),
+            INode::UnaryI(op, sub_e) => paren(op.to_string(), vec![I(sub_e)]),
+            INode::BinaryI(op, e_1, e_2) => paren(op.to_string(), vec![I(e_1), I(e_2)]),
+            INode::BinaryV(op, sub_e) => paren(op.to_string(), vec![V(sub_e)]),
+            INode::IfThenElseI(e_1, e_2, e_3) => paren("?".to_owned(), vec![I(e_1), I(e_2), I(e_3)]),
+            INode::IfThenElseV(e_1, e_2) => paren("?".to_owned(), vec![I(e_1), V(e_2)]),
+            INode::FromF(sub_e) => paren("int".to_owned(), vec![F(sub_e)]),
+        }
+        V(id) => match arena.vnode(id) {
+            VNode::Pixel | VNode::CenteredPixel | VNode::Iterand =>
+                Shape::leaf(arena.display_vexpr_within(id, lets).to_string()),
+            VNode::Swap(sub_e) => bracket("swap".to_owned(), vec![V(sub_e)]),
+            VNode::BinaryI(op_1, op_2, e_1, e_2) => bracket(format!("[{} {}]", op_1, op_2), vec![I(e_1), I(e_2)]),
+            VNode::UnaryV(op, sub_e) => bracket(op.to_string(), vec![V(sub_e)]),
+            VNode::BinaryV(op, e_1, e_2) => bracket(op.to_string(), vec![V(e_1), V(e_2)]),
+            VNode::IfThenElseI(e_1, e_2, e_3) => bracket("?".to_owned(), vec![I(e_1), V(e_2), V(e_3)]),
+            VNode::IfThenElseV(e_1, e_2, e_3) => bracket("?v".to_owned(), vec![V(e_1), V(e_2), V(e_3)]),
+            VNode::UnaryC(op, sub_e) => bracket(op.to_string(), vec![V(sub_e)]),
+            VNode::ComplexMul(e_1, e_2) => bracket("c*".to_owned(), vec![V(e_1), V(e_2)]),
+        }
+        F(id) => match arena.fnode(id) {
+            FNode::Lit(_) => Shape::leaf(arena.display_fexpr_within(id, lets).to_string()),
+            FNode::FromI(sub_e) => paren("float".to_owned(), vec![I(sub_e)]),
+            FNode::UnaryF(op, sub_e) => paren(op.to_string(), vec![F(sub_e)]),
+            FNode::BinaryF(op, e_1, e_2) => paren(op.to_string(), vec![F(e_1), F(e_2)]),
+        }
+    }
+}
+
+fn display(arena: &ExprArena, id: NodeId, lets: usize) -> String {
+    match id {
+        NodeId::I(id) => arena.display_iexpr_within(id, lets).to_string(),
+        NodeId::V(id) => arena.display_vexpr_within(id, lets).to_string(),
+        NodeId::F(id) => arena.display_fexpr_within(id, lets).to_string(),
+    }
+}
+
+/// Display a node, showing the subexpressions for which `elide` returns true
+/// as `…`.
+fn display_elided(arena: &ExprArena, shape: &Shape, elide: impl Fn(usize) -> bool) -> String {
+    let mut text = shape.parts[0].clone();
+    for (i, &(id, lets)) in shape.children.iter().enumerate() {
+        if elide(i) {
+            text.push('…');
+        } else {
+            text.push_str(&display(arena, id, lets));
+        }
+        text.push_str(&shape.parts[i + 1]);
+    }
+    text
+}
+
+/// How a node of the old expression corresponds to one of the new expression.
+enum Diff {
+    Same(NodeId),
+    /// The nodes differ at most in their operators and parameters, and their
+    /// subexpressions are compared pairwise.
+    Node { old: NodeId, new: NodeId, children: Vec<Diff> },
+    Changed { old: NodeId, new: NodeId },
+    /// A new node was wrapped around the old one, which became its
+    /// subexpression at `position`.
+    Inserted { new: NodeId, position: usize, inner: Box<Diff> },
+    /// The old node was replaced by its own subexpression at `position`.
+    Deleted { old: NodeId, position: usize, inner: Box<Diff> },
+}
+
+/// Compares nodes of an interning arena, where identical subexpressions have
+/// the same handle.
+struct Differ<'a> {
+    arena: &'a ExprArena,
+}
+
+impl Differ<'_> {
+    fn diff(&self, old: NodeId, new: NodeId, old_lets: usize, new_lets: usize) -> Diff {
+        if old == new {
+            return Diff::Same(new);
+        }
+        let (old_shape, new_shape) = (shape(self.arena, old, old_lets), shape(self.arena, new, new_lets));
+        if let Some(position) = new_shape.children.iter().position(|&(id, _)| id == old) {
+            return Diff::Inserted { new, position, inner: Box::new(Diff::Same(old)) };
+        }
+        if let Some(position) = old_shape.children.iter().position(|&(id, _)| id == new) {
+            return Diff::Deleted { old, position, inner: Box::new(Diff::Same(new)) };
+        }
+        if old_shape.matches(&new_shape) {
+            let children = old_shape.children.iter()
+                .zip(&new_shape.children)
+                .map(|(&(old, old_lets), &(new, new_lets))| self.diff(old, new, old_lets, new_lets))
+                .collect();
+            return Diff::Node { old, new, children };
+        }
+        // A node may also have been wrapped or unwrapped and then changed
+        // inside, as long as it kept its operator and parameters.
+        let same_head = |id: NodeId, id_shape: &Shape, (child, child_lets): (NodeId, usize)| {
+            id.same_type(child) && {
+                let child_shape = shape(self.arena, child, child_lets);
+                id_shape.matches(&child_shape) && id_shape.parts == child_shape.parts
+            }
+        };
+        if let Some(position) = new_shape.children.iter().position(|&child| same_head(old, &old_shape, child)) {
+            let (child, child_lets) = new_shape.children[position];
+            let inner = Box::new(self.diff(old, child, old_lets, child_lets));
+            return Diff::Inserted { new, position, inner };
+        }
+        if let Some(position) = old_shape.children.iter().position(|&child| same_head(new, &new_shape, child)) {
+            let (child, child_lets) = old_shape.children[position];
+            let inner = Box::new(self.diff(child, new, child_lets, new_lets));
+            return Diff::Deleted { old, position, inner };
+        }
+        Diff::Changed { old, new }
+    }
+}
+
+/// A single difference between two expressions. Paths lead to the affected
+/// subexpression of the new expression.
+pub enum Edit {
+    /// A node was wrapped around the subexpression at `path`. `node` shows the
+    /// new node with `…` in place of the old subexpression.
+    Inserted { path: Path, node: String },
+    /// A node was removed and replaced by one of its subexpressions, which is
+    /// now at `path`. `node` shows the removed node with `…` in place of that
+    /// subexpression.
+    Deleted { path: Path, node: String },
+    /// The subexpression at `path` was replaced. If only its operator or
+    /// parameters changed, its own subexpressions are shown as `…` and may
+    /// have edits of their own.
+    Changed { path: Path, old: String, new: String },
+}
+
+impl Display for Edit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Edit::Inserted { path, node } => {
+                write!(f, "inserted {} at ", node)?;
+                write_path(f, path)
+            }
+            Edit::Deleted { path, node } => {
+                write!(f, "deleted {} at ", node)?;
+                write_path(f, path)
+            }
+            Edit::Changed { path, old, new } => {
+                write!(f, "changed {} to {} at ", old, new)?;
+                write_path(f, path)
+            }
+        }
+    }
+}
+
+/// How removed and added text is marked when rendering a diff.
+#[derive(Clone, Copy)]
+enum Markup {
+    /// In the style of `git diff --word-diff`.
+    Text,
+    Html,
+}
+
+impl Markup {
+    fn push_text(self, out: &mut String, text: &str) {
+        match self {
+            Markup::Text => out.push_str(text),
+            Markup::Html => out.push_str(&utils::escape_html(text)),
+        }
+    }
+    fn push_deleted(self, out: &mut String, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        out.push_str(match self { Markup::Text => "[-", Markup::Html => "<del>" });
+        self.push_text(out, text);
+        out.push_str(match self { Markup::Text => "-]", Markup::Html => "</del>" });
+    }
+    fn push_inserted(self, out: &mut String, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        out.push_str(match self { Markup::Text => "{+", Markup::Html => "<ins>" });
+        self.push_text(out, text);
+        out.push_str(match self { Markup::Text => "+}", Markup::Html => "</ins>" });
+    }
+    fn push_changed(self, out: &mut String, old: &str, new: &str) {
+        if old == new {
+            self.push_text(out, old);
+        } else {
+            self.push_deleted(out, old);
+            self.push_inserted(out, new);
+        }
+    }
+}
+
+/// The differences between two integer expressions.
+pub struct ExprDiff {
+    arena: ExprArena,
+    root: Diff,
+}
+
+impl ExprDiff {
+    pub fn edits(&self) -> Vec<Edit> {
+        let mut edits = Vec::new();
+        self.collect_edits(&self.root, &mut Vec::new(), 0, 0, &mut edits);
+        edits
+    }
+    fn collect_edits(&self, diff: &Diff, path: &mut Path, old_lets: usize, new_lets: usize, edits: &mut Vec<Edit>) {
+        let arena = &self.arena;
+        match diff {
+            Diff::Same(_) => {}
+            Diff::Node { old, new, children } => {
+                let (old_shape, new_shape) = (shape(arena, *old, old_lets), shape(arena, *new, new_lets));
+                if old_shape.parts != new_shape.parts {
+                    edits.push(Edit::Changed {
+                        path: path.clone(),
+                        old: display_elided(arena, &old_shape, |_| true),
+                        new: display_elided(arena, &new_shape, |_| true),
+                    });
+                }
+                for (i, child) in children.iter().enumerate() {
+                    path.push(i);
+                    self.collect_edits(child, path, old_shape.children[i].1, new_shape.children[i].1, edits);
+                    path.pop();
+                }
+            }
+            Diff::Changed { old, new } => edits.push(Edit::Changed {
+                path: path.clone(),
+                old: display(arena, *old, old_lets),
+                new: display(arena, *new, new_lets),
+            }),
+            Diff::Inserted { new, position, inner } => {
+                let new_shape = shape(arena, *new, new_lets);
+                edits.push(Edit::Inserted {
+                    path: path.clone(),
+                    node: display_elided(arena, &new_shape, |i| i == *position),
+                });
+                path.push(*position);
+                self.collect_edits(inner, path, old_lets, new_shape.children[*position].1, edits);
+                path.pop();
+            }
+            Diff::Deleted { old, position, inner } => {
+                let old_shape = shape(arena, *old, old_lets);
+                edits.push(Edit::Deleted {
+                    path: path.clone(),
+                    node: display_elided(arena, &old_shape, |i| i == *position),
+                });
+                self.collect_edits(inner, path, old_shape.children[*position].1, new_lets, edits);
+            }
+        }
+    }
+
+    /// Show the new expression in the format it's usually displayed in, with
+    /// removed text marked as `[-...-]` and added text as `{+...+}`.
+    pub fn to_text(&self) -> String {
+        self.render(Markup::Text)
+    }
+    /// Like `to_text`, but as HTML with `del` and `ins` elements.
+    pub fn to_html(&self) -> String {
+        self.render(Markup::Html)
+    }
+    fn render(&self, markup: Markup) -> String {
+        let mut out = String::new();
+        self.render_diff(&self.root, markup, 0, 0, &mut out);
+        out
+    }
+    fn render_diff(&self, diff: &Diff, markup: Markup, old_lets: usize, new_lets: usize, out: &mut String) {
+        let arena = &self.arena;
+        match diff {
+            Diff::Same(id) => markup.push_text(out, &display(arena, *id, new_lets)),
+            Diff::Node { new: NodeId::I(new), .. } if matches!(arena.inode(*new), INode::Let(..)) =>
+                self.render_lets(diff, markup, old_lets, new_lets, out),
+            Diff::Node { old, new, children } => {
+                let (old_shape, new_shape) = (shape(arena, *old, old_lets), shape(arena, *new, new_lets));
+                for (i, child) in children.iter().enumerate() {
+                    markup.push_changed(out, &old_shape.parts[i], &new_shape.parts[i]);
+                    self.render_diff(child, markup, old_shape.children[i].1, new_shape.children[i].1, out);
+                }
+                markup.push_changed(out, old_shape.parts.last().unwrap(), new_shape.parts.last().unwrap());
+            }
+            Diff::Changed { old, new } => {
+                markup.push_deleted(out, &display(arena, *old, old_lets));
+                markup.push_inserted(out, &display(arena, *new, new_lets));
+            }
+            Diff::Inserted { new, position, inner } => {
+                let new_shape = shape(arena, *new, new_lets);
+                let (before, after) = split_around(arena, &new_shape, *position);
+                markup.push_inserted(out, &before);
+                self.render_diff(inner, markup, old_lets, new_shape.children[*position].1, out);
+                markup.push_inserted(out, &after);
+            }
+            Diff::Deleted { old, position, inner } => {
+                let old_shape = shape(arena, *old, old_lets);
+                let (before, after) = split_around(arena, &old_shape, *position);
+                markup.push_deleted(out, &before);
+                self.render_diff(inner, markup, old_shape.children[*position].1, new_lets, out);
+                markup.push_deleted(out, &after);
+            }
+        }
+    }
+    /// Render a chain of matching `Let`s as a single `let` with several
+    /// bindings, the way they're usually displayed.
+    fn render_lets(&self, mut diff: &Diff, markup: Markup, mut old_lets: usize, mut new_lets: usize, out: &mut String) {
+        markup.push_text(out, "(let (");
+        let mut first = true;
+        while let Diff::Node { new: NodeId::I(new), children, .. } = diff {
+            if !matches!(self.arena.inode(*new), INode::Let(..)) {
+                break;
+            }
+            if !first {
+                markup.push_text(out, " ");
+            }
+            first = false;
+            markup.push_changed(out, &format!("(v{} ", old_lets), &format!("(v{} ", new_lets));
+            self.render_diff(&children[0], markup, old_lets, new_lets, out);
+            markup.push_text(out, ")");
+            diff = &children[1];
+            old_lets += 1;
+            new_lets += 1;
+        }
+        markup.push_text(out, ") ");
+        self.render_diff(diff, markup, old_lets, new_lets, out);
+        markup.push_text(out, ")");
+    }
+}
+
+/// The text of a node before and after its subexpression at `position`.
+fn split_around(arena: &ExprArena, shape: &Shape, position: usize) -> (String, String) {
+    let mut before = shape.parts[0].clone();
+    for i in 0..position {
+        before.push_str(&display(arena, shape.children[i].0, shape.children[i].1));
+        before.push_str(&shape.parts[i + 1]);
+    }
+    let mut after = shape.parts[position + 1].clone();
+    for i in position + 1..shape.children.len() {
+        after.push_str(&display(arena, shape.children[i].0, shape.children[i].1));
+        after.push_str(&shape.parts[i + 1]);
+    }
+    (before, after)
+}
+
+impl IExpr {
+    /// Find how `new` differs from this expression, as a tree diff: matching
+    /// nodes are compared by their subexpressions, and nodes that were
+    /// wrapped around or unwrapped from a subexpression are told apart from
+    /// ones that were replaced outright.
+    pub fn diff(&self, new: &IExpr) -> ExprDiff {
+        let mut arena = ExprArena::interning();
+        let old = arena.insert_iexpr(self);
+        let new = arena.insert_iexpr(new);
+        let root = Differ { arena: &arena }.diff(NodeId::I(old), NodeId::I(new), 0, 0);
+        ExprDiff { arena, root }
+    }
+}
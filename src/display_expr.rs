@@ -1,6 +1,18 @@
 use std::fmt::{self, Display, Formatter};
 
-use crate::expr::{IExpr, VExpr, Unary, Binary};
+use crate::arena::{ExprArena, IExprId, VExprId, FExprId, INode, VNode, FNode};
+use crate::expr::{IExpr, VExpr, FExpr, Normalization, Unary, Binary, ComplexUnary, FUnary, FBinary};
+
+impl Display for Normalization {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Normalization::*;
+        match self {
+            Percentile(p) => write!(f, "percentile-{}", p),
+            Equalize => write!(f, "equalize"),
+            Rank => write!(f, "rank"),
+        }
+    }
+}
 
 impl Display for Unary {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -14,6 +26,10 @@ impl Display for Unary {
             ModBy(n) => write!(f, "%{}", n),
             Mod256 => write!(f, "mod-256"),
             Clamp256 => write!(f, "clamp"),
+            Popcount => write!(f, "popcount"),
+            Sqrt => write!(f, "isqrt"),
+            Log2 => write!(f, "ilog2"),
+            Not => write!(f, "not"),
         }
     }
 }
@@ -28,40 +44,264 @@ impl Display for Binary {
             BitAnd => write!(f, "&"),
             BitOr  => write!(f, "|"),
             BitXor => write!(f, "^"),
+            Shl => write!(f, "<<"),
+            Shr => write!(f, ">>"),
+            RotL => write!(f, "rotl"),
+            Min => write!(f, "min"),
+            Max => write!(f, "max"),
+            Lt => write!(f, "<"),
+            Eq => write!(f, "="),
+            Div => write!(f, "/"),
+            Mod => write!(f, "%"),
         }
     }
 }
 
-impl Display for IExpr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use IExpr::*;
+impl Display for ComplexUnary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use ComplexUnary::*;
+        match self {
+            Conj => write!(f, "conj"),
+            Square => write!(f, "csquare"),
+            SquaredMagnitude => write!(f, "cnorm"),
+        }
+    }
+}
+
+impl Display for FUnary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use FUnary::*;
         match self {
+            Sin => write!(f, "sin"),
+            Cos => write!(f, "cos"),
+            Sqrt => write!(f, "sqrt"),
+            Exp => write!(f, "exp"),
+        }
+    }
+}
+
+impl Display for FBinary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use FBinary::*;
+        match self {
+            Add => write!(f, "+."),
+            Sub => write!(f, "-."),
+            Mul => write!(f, "*."),
+            Div => write!(f, "/."),
+            Atan2 => write!(f, "atan2"),
+        }
+    }
+}
+
+// Each of these keeps track of the number of `Let`s around the node being
+// displayed, which is used to name variables. The variable bound by a `Let`
+// is named after the number of `Let`s around it, so names never shadow each
+// other.
+
+/// Displays the integer expression rooted at a node of an `ExprArena`.
+pub struct DisplayIExpr<'a> {
+    arena: &'a ExprArena,
+    id: IExprId,
+    depth: usize,
+}
+
+/// Displays the pair expression rooted at a node of an `ExprArena`.
+pub struct DisplayVExpr<'a> {
+    arena: &'a ExprArena,
+    id: VExprId,
+    depth: usize,
+}
+
+/// Displays the float expression rooted at a node of an `ExprArena`.
+pub struct DisplayFExpr<'a> {
+    arena: &'a ExprArena,
+    id: FExprId,
+    depth: usize,
+}
+
+impl ExprArena {
+    pub fn display_iexpr(&self, id: IExprId) -> DisplayIExpr<'_> {
+        DisplayIExpr { arena: self, id, depth: 0 }
+    }
+    pub fn display_vexpr(&self, id: VExprId) -> DisplayVExpr<'_> {
+        DisplayVExpr { arena: self, id, depth: 0 }
+    }
+    pub fn display_fexpr(&self, id: FExprId) -> DisplayFExpr<'_> {
+        DisplayFExpr { arena: self, id, depth: 0 }
+    }
+    /// Like `display_iexpr`, for a node inside the given number of `Let`s,
+    /// which its variables are named after.
+    pub fn display_iexpr_within(&self, id: IExprId, lets: usize) -> DisplayIExpr<'_> {
+        DisplayIExpr { arena: self, id, depth: lets }
+    }
+    pub fn display_vexpr_within(&self, id: VExprId, lets: usize) -> DisplayVExpr<'_> {
+        DisplayVExpr { arena: self, id, depth: lets }
+    }
+    pub fn display_fexpr_within(&self, id: FExprId, lets: usize) -> DisplayFExpr<'_> {
+        DisplayFExpr { arena: self, id, depth: lets }
+    }
+}
+
+impl Display for DisplayIExpr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use INode::*;
+        let (arena, depth) = (self.arena, self.depth);
+        let i = |id| DisplayIExpr { arena, id, depth };
+        let v = |id| DisplayVExpr { arena, id, depth };
+        let fl = |id| DisplayFExpr { arena, id, depth };
+        match self.arena.inode(self.id) {
             Lit(n) => write!(f, "{}", n),
             Rgb([r, g, b]) => write!(f, "{}/{}/{}", r, g, b),
             PixelX => write!(f, "x"),
             PixelY => write!(f, "y"),
             Channel => write!(f, "c"),
-            Scale256(sub_e) => write!(f, "(scale-256 {})", sub_e),
-            UnaryI(op, sub_e) => write!(f, "({} {})", op, sub_e),
-            BinaryI(op, e_1, e_2) => write!(f, "({} {} {})", op, e_1, e_2),
-            BinaryV(op, sub_e) => write!(f, "({} {})", op, sub_e),
-            IfThenElseI(e_1, e_2, e_3) => write!(f, "(? {} {} {})", e_1, e_2, e_3),
-            IfThenElseV(e_1, e_2) => write!(f, "(? {} {})", e_1, e_2),
+            Time => write!(f, "t"),
+            Radius => write!(f, "r"),
+            Angle => write!(f, "a"),
+            Noise(seed, scale, sub_e) => write!(f, "(noise {} {} {})", seed, scale, v(sub_e)),
+            Let(..) => {
+                // Nested `Let`s are shown as a single `let` with several bindings.
+                write!(f, "(let (")?;
+                let (mut id, mut depth) = (self.id, depth);
+                while let Let(e_value, e_body) = arena.inode(id) {
+                    if depth != self.depth {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "(v{} {})", depth, DisplayIExpr { arena, id: e_value, depth })?;
+                    id = e_body;
+                    depth += 1;
+                }
+                write!(f, ") {})", DisplayIExpr { arena, id, depth })
+            }
+            Normalize(kind, sub_e) => write!(f, "({} {})", kind, i(sub_e)),
+            Offset(dx, dy, sub_e) => write!(f, "(offset {} {} {})", dx, dy, i(sub_e)),
+            Blur(radius, sub_e) => write!(f, "(blur {} {})", radius, i(sub_e)),
+            Sobel(sub_e) => write!(f, "(sobel {})", i(sub_e)),
+            Iterate(n, e_body, e_init) => write!(f, "(iterate {} {} {})", n, v(e_body), v(e_init)),
+            // A variable without a binding is shown by its index, which
+            // parses back to the same variable.
+            Var(n) => match depth.checked_sub(n as usize + 1) {
+                Some(binding) => write!(f, "v{}", binding),
+                None => write!(f, "?{}", n),
+            },
+            Scale256(sub_e) => write!(f, "(scale-256 {})", i(sub_e)),
+            UnaryI(op, sub_e) => write!(f, "({} {})", op, i(sub_e)),
+            BinaryI(op, e_1, e_2) => write!(f, "({} {} {})", op, i(e_1), i(e_2)),
+            BinaryV(op, sub_e) => write!(f, "({} {})", op, v(sub_e)),
+            IfThenElseI(e_1, e_2, e_3) => write!(f, "(? {} {} {})", i(e_1), i(e_2), i(e_3)),
+            IfThenElseV(e_1, e_2) => write!(f, "(? {} {})", i(e_1), v(e_2)),
+            FromF(sub_e) => write!(f, "(int {})", fl(sub_e)),
         }
     }
 }
 
-impl Display for VExpr {
+impl Display for DisplayVExpr<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use VExpr::*;
-        match self {
+        use VNode::*;
+        let (arena, depth) = (self.arena, self.depth);
+        let i = |id| DisplayIExpr { arena, id, depth };
+        let v = |id| DisplayVExpr { arena, id, depth };
+        match self.arena.vnode(self.id) {
             Pixel => write!(f, "xy"),
-            Swap(sub_e) => write!(f, "[swap {}]", sub_e),
-            BinaryI(op_1, op_2, e_1, e_2) => write!(f, "[[{} {}] {} {}]", op_1, op_2, e_1, e_2),
-            UnaryV(op, sub_e) => write!(f, "[{} {}]", op, sub_e),
-            BinaryV(op, e_1, e_2) => write!(f, "[{} {} {}]", op, e_1, e_2),
-            IfThenElseI(e_1, e_2, e_3) => write!(f, "[? {} {} {}]", e_1, e_2, e_3),
-            IfThenElseV(e_1, e_2, e_3) => write!(f, "[? {} {} {}]", e_1, e_2, e_3),
+            CenteredPixel => write!(f, "cxy"),
+            Iterand => write!(f, "z"),
+            Swap(sub_e) => write!(f, "[swap {}]", v(sub_e)),
+            BinaryI(op_1, op_2, e_1, e_2) => write!(f, "[[{} {}] {} {}]", op_1, op_2, i(e_1), i(e_2)),
+            UnaryV(op, sub_e) => write!(f, "[{} {}]", op, v(sub_e)),
+            BinaryV(op, e_1, e_2) => write!(f, "[{} {} {}]", op, v(e_1), v(e_2)),
+            IfThenElseI(e_1, e_2, e_3) => write!(f, "[? {} {} {}]", i(e_1), v(e_2), v(e_3)),
+            IfThenElseV(e_1, e_2, e_3) => write!(f, "[?v {} {} {}]", v(e_1), v(e_2), v(e_3)),
+            UnaryC(op, sub_e) => write!(f, "[{} {}]", op, v(sub_e)),
+            ComplexMul(e_1, e_2) => write!(f, "[c* {} {}]", v(e_1), v(e_2)),
+        }
+    }
+}
+
+impl Display for DisplayFExpr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use FNode::*;
+        let (arena, depth) = (self.arena, self.depth);
+        let i = |id| DisplayIExpr { arena, id, depth };
+        let fl = |id| DisplayFExpr { arena, id, depth };
+        match self.arena.fnode(self.id) {
+            // The `Debug` format always includes a decimal point, which tells
+            // float literals apart from integer literals. It leaves out the
+            // sign of NaN, which folding constants can set.
+            Lit(n) if n.is_nan() && n.is_sign_negative() => write!(f, "-NaN"),
+            Lit(n) => write!(f, "{:?}", n),
+            FromI(sub_e) => write!(f, "(float {})", i(sub_e)),
+            UnaryF(op, sub_e) => write!(f, "({} {})", op, fl(sub_e)),
+            BinaryF(op, e_1, e_2) => write!(f, "({} {} {})", op, fl(e_1), fl(e_2)),
+        }
+    }
+}
+
+impl Display for IExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (arena, root) = ExprArena::from_iexpr(self);
+        arena.display_iexpr(root).fmt(f)
+    }
+}
+
+impl Display for VExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut arena = ExprArena::new();
+        let root = arena.insert_vexpr(self);
+        arena.display_vexpr(root).fmt(f)
+    }
+}
+
+impl Display for FExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut arena = ExprArena::new();
+        let root = arena.insert_fexpr(self);
+        arena.display_fexpr(root).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::expr::{IExpr, FExpr, Unary};
+    use crate::gen_expr::Parameters;
+
+    fn assert_round_trips(e: &IExpr) {
+        let src = e.to_string();
+        match src.parse::<IExpr>() {
+            Ok(parsed) => assert!(parsed == *e, "`{}` parsed as `{}`", src, parsed),
+            Err(err) => panic!("`{}` doesn't parse: {}", src, err),
         }
     }
+
+    #[test]
+    fn generated_expressions_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let params = Parameters::default();
+        for _ in 0..500 {
+            let e = params.gen_expr(&mut rng, 8, 3);
+            assert_round_trips(&e);
+            assert_round_trips(&e.clone().simplify());
+            assert_round_trips(&e.share_common_subexpressions());
+        }
+    }
+
+    #[test]
+    fn edge_literals_round_trip() {
+        for &n in &[f32::NAN, -f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1e20, -0.0, f32::MAX, f32::MIN_POSITIVE] {
+            assert_round_trips(&IExpr::FromF(Box::new(FExpr::Lit(n))));
+        }
+        for &n in &[i32::MIN, i32::MAX, -1] {
+            assert_round_trips(&IExpr::Lit(n));
+        }
+        assert_round_trips(&IExpr::Rgb([0, 128, 255]));
+    }
+
+    #[test]
+    fn unbound_variables_round_trip() {
+        assert_round_trips(&IExpr::Var(0));
+        let body = IExpr::UnaryI(Unary::Neg, Box::new(IExpr::Var(3)));
+        assert_round_trips(&IExpr::Let(Box::new(IExpr::Var(1)), Box::new(body)));
+    }
 }
@@ -1,11 +1,13 @@
 use serde::{Serialize, Deserialize};
 
+use std::hash::{Hash, Hasher};
+
 use crate::utils::clamp;
 
-type Color = [i32; 3];
+pub type Color = [i32; 3];
 
 /// An expression that returns a single 32-bit integer.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum IExpr {
     Lit(i32),
     Rgb([u8; 3]),
@@ -18,10 +20,51 @@ pub enum IExpr {
     BinaryV(Binary, Box<VExpr>),
     IfThenElseI(Box<IExpr>, Box<IExpr>, Box<IExpr>),
     IfThenElseV(Box<IExpr>, Box<VExpr>),
+    /// The index of the current frame of an animation.
+    Time,
+    /// Convert a float to an integer, rounding towards zero. Out-of-range
+    /// values saturate and NaN becomes 0.
+    FromF(Box<FExpr>),
+    /// The distance from the center of the image, rounded down.
+    Radius,
+    /// The clockwise angle from the positive x-axis around the center of the
+    /// image, where a full turn is 256.
+    Angle,
+    /// Value noise with the given seed and lattice spacing, sampled at the
+    /// point given by the pair.
+    Noise(u32, u8, Box<VExpr>),
+    /// Evaluate the first expression once and make its value available to the
+    /// second as `Var(0)`.
+    Let(Box<IExpr>, Box<IExpr>),
+    /// The value bound by an enclosing `Let`, counting outwards from 0 for the
+    /// innermost one.
+    Var(u32),
+    /// Map each channel onto the range 0 to 255 based on the distribution of
+    /// its values over the whole image, like `Scale256`.
+    Normalize(Normalization, Box<IExpr>),
+    /// The value of the expression at the pixel the given number of columns
+    /// to the right and rows down. Pixels past the edge of the image take the
+    /// value of the nearest pixel on the edge.
+    ///
+    /// This and the other neighborhood nodes work in pixels of the rendered
+    /// image rather than in coordinates, so unlike the rest of an expression
+    /// their effect changes when the viewport is resized or zoomed.
+    Offset(i8, i8, Box<IExpr>),
+    /// The mean of the expression over the square of pixels within the given
+    /// distance, rounded down.
+    Blur(u8, Box<IExpr>),
+    /// The magnitude of the gradient of the expression found by the Sobel
+    /// operator from the neighboring pixels, rounded down.
+    Sobel(Box<IExpr>),
+    /// Apply the first expression, a loop body, to the pair given by the
+    /// second up to the given number of times. The result is the number of
+    /// times the body was applied before its result escaped, or the maximum
+    /// if it never did.
+    Iterate(u8, Box<VExpr>, Box<VExpr>),
 }
 
 /// An expression that returns a pair of 32-bit integers.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum VExpr {
     Pixel,
     Swap(Box<VExpr>),
@@ -30,9 +73,103 @@ pub enum VExpr {
     BinaryV(Binary, Box<VExpr>, Box<VExpr>),
     IfThenElseI(Box<IExpr>, Box<VExpr>, Box<VExpr>),
     IfThenElseV(Box<VExpr>, Box<VExpr>, Box<VExpr>),
+    /// The coordinates of the pixel relative to the center of the image.
+    CenteredPixel,
+    /// The pair that the innermost enclosing loop body is being applied to.
+    Iterand,
+    /// Apply an operator to the pair read as a complex number.
+    UnaryC(ComplexUnary, Box<VExpr>),
+    /// The product of the pairs read as complex numbers.
+    ComplexMul(Box<VExpr>, Box<VExpr>),
+}
+
+/// Complex operators read a pair as the real and imaginary parts of a fixed
+/// point number with this many fractional bits, so that 64 stands for 1.
+/// Adding pairs lane by lane already adds them as complex numbers.
+pub const FIXED_POINT_BITS: u32 = 6;
+
+/// Multiply two fixed point complex numbers, wrapping on overflow.
+pub fn complex_mul((a, b): (i32, i32), (c, d): (i32, i32)) -> (i32, i32) {
+    let (a, b, c, d) = (a as i64, b as i64, c as i64, d as i64);
+    (
+        (a.wrapping_mul(c).wrapping_sub(b.wrapping_mul(d)) >> FIXED_POINT_BITS) as i32,
+        (a.wrapping_mul(d).wrapping_add(b.wrapping_mul(c)) >> FIXED_POINT_BITS) as i32,
+    )
+}
+
+/// Replace each complex number whose parts are the corresponding elements of
+/// `res_a` and `ims_a` with its product with the one in `res_b` and `ims_b`.
+pub fn complex_mul_slices(res_a: &mut [i32], ims_a: &mut [i32], res_b: &[i32], ims_b: &[i32]) {
+    res_a.iter_mut().zip(ims_a).zip(res_b.iter().zip(ims_b)).for_each(|((re_a, im_a), (&re_b, &im_b))| {
+        (*re_a, *im_a) = complex_mul((*re_a, *im_a), (re_b, im_b));
+    });
+}
+
+/// A pair escapes from an `Iterate` once either element is further than this
+/// from 0. This is low enough that squaring an element can't overflow.
+pub const ESCAPE_BOUND: u32 = 1 << 14;
+
+/// An expression that returns a single 32-bit float.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum FExpr {
+    Lit(f32),
+    FromI(Box<IExpr>),
+    UnaryF(FUnary, Box<FExpr>),
+    BinaryF(FBinary, Box<FExpr>, Box<FExpr>),
+}
+
+// Float literals are compared by their bit patterns, so that every literal is
+// equal to itself, including NaN.
+impl PartialEq for FExpr {
+    fn eq(&self, other: &Self) -> bool {
+        use FExpr::*;
+        match (self, other) {
+            (Lit(a), Lit(b)) => a.to_bits() == b.to_bits(),
+            (FromI(a), FromI(b)) => a == b,
+            (UnaryF(op_a, a), UnaryF(op_b, b)) => op_a == op_b && a == b,
+            (BinaryF(op_a, a_1, a_2), BinaryF(op_b, b_1, b_2)) => op_a == op_b && a_1 == b_1 && a_2 == b_2,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for FExpr {}
+
+impl Hash for FExpr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use FExpr::*;
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Lit(n) => n.to_bits().hash(state),
+            FromI(sub_e) => sub_e.hash(state),
+            UnaryF(op, sub_e) => {
+                op.hash(state);
+                sub_e.hash(state);
+            }
+            BinaryF(op, e_1, e_2) => {
+                op.hash(state);
+                e_1.hash(state);
+                e_2.hash(state);
+            }
+        }
+    }
+}
+
+/// A way of mapping values onto the range 0 to 255. Each of these gives 127
+/// when every value is the same.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Normalization {
+    /// Clip values below the given percentile and above the same distance
+    /// from the top, then stretch the rest linearly. Must be less than 50.
+    Percentile(u8),
+    /// Histogram equalization: spread values out according to the fraction of
+    /// values at or below them, so that common values get more of the range.
+    Equalize,
+    /// Space the distinct values evenly, ignoring how far apart they are.
+    Rank,
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Unary {
     Square,
     Cube,
@@ -42,10 +179,18 @@ pub enum Unary {
     ModBy(u8),
     Mod256,
     Clamp256,
+    /// The number of set bits.
+    Popcount,
+    /// The square root of the absolute value, rounded down, with the sign of
+    /// the original value.
+    Sqrt,
+    /// The base 2 logarithm of the absolute value, rounded down. Gives -1 for 0.
+    Log2,
+    Not,
 }
 
 impl Unary {
-    fn eval(self, n: i32) -> i32 {
+    pub fn eval(self, n: i32) -> i32 {
         use Unary::*;
         match self {
             Square => n.wrapping_mul(n),
@@ -56,11 +201,37 @@ impl Unary {
             ModBy(d) => n.wrapping_rem_euclid(d as i32),
             Mod256 => n.wrapping_rem_euclid(256),
             Clamp256 => clamp(0, 255, n),
+            Popcount => n.count_ones() as i32,
+            Sqrt => n.signum() * n.unsigned_abs().isqrt() as i32,
+            Log2 => n.unsigned_abs().checked_ilog2().map_or(-1, |log| log as i32),
+            Not => !n,
+        }
+    }
+    /// Apply the operator to every element of `ns`. Matching on the operator
+    /// outside of the loop keeps each loop simple enough to be vectorized.
+    pub fn eval_slice(self, ns: &mut [i32]) {
+        use Unary::*;
+        fn map(ns: &mut [i32], f: impl Fn(i32) -> i32) {
+            ns.iter_mut().for_each(|n| *n = f(*n));
+        }
+        match self {
+            Square => map(ns, |n| Square.eval(n)),
+            Cube => map(ns, |n| Cube.eval(n)),
+            Abs => map(ns, |n| Abs.eval(n)),
+            Neg => map(ns, |n| Neg.eval(n)),
+            DivBy(d) => map(ns, |n| DivBy(d).eval(n)),
+            ModBy(d) => map(ns, |n| ModBy(d).eval(n)),
+            Mod256 => map(ns, |n| Mod256.eval(n)),
+            Clamp256 => map(ns, |n| Clamp256.eval(n)),
+            Popcount => map(ns, |n| Popcount.eval(n)),
+            Sqrt => map(ns, |n| Sqrt.eval(n)),
+            Log2 => map(ns, |n| Log2.eval(n)),
+            Not => map(ns, |n| Not.eval(n)),
         }
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Binary {
     Add,
     Sub,
@@ -68,10 +239,31 @@ pub enum Binary {
     BitAnd,
     BitOr,
     BitXor,
+    /// Shift left by the second operand modulo 32.
+    Shl,
+    /// Shift right, preserving the sign, by the second operand modulo 32.
+    Shr,
+    /// Rotate left by the second operand modulo 32.
+    RotL,
+    Min,
+    Max,
+    /// 1 if the first operand is less than the second and 0 otherwise.
+    Lt,
+    /// 1 if the operands are equal and 0 otherwise.
+    Eq,
+    /// Euclidean division, giving 0 when dividing by 0.
+    Div,
+    /// The Euclidean remainder, giving 0 when dividing by 0.
+    Mod,
 }
 
 impl Binary {
-    fn eval(self, a: i32, b: i32) -> i32 {
+    /// Whether swapping the operands never changes the result.
+    pub fn is_commutative(self) -> bool {
+        use Binary::*;
+        matches!(self, Add | Mul | BitAnd | BitOr | BitXor | Min | Max | Eq)
+    }
+    pub fn eval(self, a: i32, b: i32) -> i32 {
         use Binary::*;
         match self {
             Add => a.wrapping_add(b),
@@ -80,313 +272,169 @@ impl Binary {
             BitAnd => a & b,
             BitOr => a | b,
             BitXor => a ^ b,
+            Shl => a.wrapping_shl(b as u32),
+            Shr => a.wrapping_shr(b as u32),
+            RotL => a.rotate_left(b as u32),
+            Min => std::cmp::min(a, b),
+            Max => std::cmp::max(a, b),
+            Lt => (a < b) as i32,
+            Eq => (a == b) as i32,
+            Div => if b == 0 { 0 } else { a.wrapping_div_euclid(b) },
+            Mod => if b == 0 { 0 } else { a.wrapping_rem_euclid(b) },
+        }
+    }
+    /// Replace each element of `a` with the result of applying the operator
+    /// to it and the corresponding element of `b`.
+    pub fn eval_slices(self, a: &mut [i32], b: &[i32]) {
+        use Binary::*;
+        fn zip(a: &mut [i32], b: &[i32], f: impl Fn(i32, i32) -> i32) {
+            a.iter_mut().zip(b).for_each(|(a, &b)| *a = f(*a, b));
+        }
+        match self {
+            Add => zip(a, b, |a, b| Add.eval(a, b)),
+            Sub => zip(a, b, |a, b| Sub.eval(a, b)),
+            Mul => zip(a, b, |a, b| Mul.eval(a, b)),
+            BitAnd => zip(a, b, |a, b| BitAnd.eval(a, b)),
+            BitOr => zip(a, b, |a, b| BitOr.eval(a, b)),
+            BitXor => zip(a, b, |a, b| BitXor.eval(a, b)),
+            Shl => zip(a, b, |a, b| Shl.eval(a, b)),
+            Shr => zip(a, b, |a, b| Shr.eval(a, b)),
+            RotL => zip(a, b, |a, b| RotL.eval(a, b)),
+            Min => zip(a, b, |a, b| Min.eval(a, b)),
+            Max => zip(a, b, |a, b| Max.eval(a, b)),
+            Lt => zip(a, b, |a, b| Lt.eval(a, b)),
+            Eq => zip(a, b, |a, b| Eq.eval(a, b)),
+            Div => zip(a, b, |a, b| Div.eval(a, b)),
+            Mod => zip(a, b, |a, b| Mod.eval(a, b)),
         }
     }
 }
 
-#[derive(Clone, Copy)]
-enum StackItem {
-    Same(i32),
-    Rgb(i32, i32, i32),
+/// An operator on a pair read as a fixed point complex number.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ComplexUnary {
+    /// The complex conjugate, which negates the imaginary part.
+    Conj,
+    Square,
+    /// The square of the absolute value, as a real number.
+    SquaredMagnitude,
 }
 
-impl StackItem {
-    fn rgb(self) -> Color {
-        use StackItem::*;
+impl ComplexUnary {
+    pub fn eval(self, (re, im): (i32, i32)) -> (i32, i32) {
+        use ComplexUnary::*;
         match self {
-            Same(n) => [n, n, n],
-            Rgb(r, g, b) => [r, g, b]
+            Conj => (re, im.wrapping_neg()),
+            Square => complex_mul((re, im), (re, im)),
+            SquaredMagnitude => {
+                let (re, im) = (re as i64, im as i64);
+                ((re.wrapping_mul(re).wrapping_add(im.wrapping_mul(im)) >> FIXED_POINT_BITS) as i32, 0)
+            }
         }
     }
-    fn eval_unary(self, op: Unary) -> Self {
-        use StackItem::*;
-        match self {
-            Same(n) => Same(op.eval(n)),
-            Rgb(r, g, b) => Rgb(op.eval(r), op.eval(g), op.eval(b)),
+    /// Apply the operator to every complex number whose parts are the
+    /// corresponding elements of `res` and `ims`.
+    pub fn eval_slices(self, res: &mut [i32], ims: &mut [i32]) {
+        use ComplexUnary::*;
+        fn map(res: &mut [i32], ims: &mut [i32], f: impl Fn((i32, i32)) -> (i32, i32)) {
+            res.iter_mut().zip(ims).for_each(|(re, im)| (*re, *im) = f((*re, *im)));
         }
-    }
-    fn eval_binary(self, other: Self, op: Binary) -> Self {
-        use StackItem::*;
-        let eval = |a, b| op.eval(a, b);
-        match (self, other) {
-            (Same(n1), Same(n2)) => Same(eval(n1, n2)),
-            (Same(n), Rgb(r, g, b)) =>
-                Rgb(eval(n, r), eval(n, g), eval(n, b)),
-            (Rgb(r, g, b), Same(n)) =>
-                Rgb(eval(r, n), eval(g, n), eval(b, n)),
-            (Rgb(r1, g1, b1), Rgb(r2, g2, b2)) =>
-                Rgb(eval(r1, r2), eval(g1, g2), eval(b1, b2)),
+        match self {
+            Conj => map(res, ims, |z| Conj.eval(z)),
+            Square => map(res, ims, |z| Square.eval(z)),
+            SquaredMagnitude => map(res, ims, |z| SquaredMagnitude.eval(z)),
         }
     }
 }
 
-impl From<i32> for StackItem {
-    fn from(i: i32) -> Self {
-        Self::Same(i)
-    }
-}
-
-impl From<Color> for StackItem {
-    fn from([r, g, b]: Color) -> Self {
-        Self::Rgb(r, g, b)
-    }
-}
-
-struct BatchEntry {
-    pos: (i32, i32),
-    stack: Vec<StackItem>,
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FUnary {
+    Sin,
+    Cos,
+    Sqrt,
+    Exp,
 }
 
-impl BatchEntry {
-    fn pop(&mut self) -> StackItem {
-        self.stack.pop().unwrap()
-    }
-    fn pop_2(&mut self) -> (StackItem, StackItem) {
-        let a = self.pop();
-        let b = self.pop();
-        (b, a)
-    }
-    fn get(&self, idx_from_back: usize) -> StackItem {
-        let idx = self.len() - 1 - idx_from_back;
-        self.stack[idx]
-    }
-    fn get_mut(&mut self, idx_from_back: usize) -> &mut StackItem {
-        let idx = self.len() - 1 - idx_from_back;
-        &mut self.stack[idx]
-    }
-    fn len(&self) -> usize {
-        self.stack.len()
+impl FUnary {
+    pub fn eval(self, n: f32) -> f32 {
+        use FUnary::*;
+        match self {
+            Sin => n.sin(),
+            Cos => n.cos(),
+            Sqrt => n.sqrt(),
+            Exp => n.exp(),
+        }
     }
-    fn push(&mut self, item: impl Into<StackItem>) {
-        self.stack.push(item.into());
+    /// Apply the operator to every element of `ns`, which hold the bit
+    /// patterns of floats.
+    pub fn eval_slice(self, ns: &mut [i32]) {
+        use FUnary::*;
+        fn map(ns: &mut [i32], f: impl Fn(f32) -> f32) {
+            ns.iter_mut().for_each(|n| *n = f(f32::from_bits(*n as u32)).to_bits() as i32);
+        }
+        match self {
+            Sin => map(ns, |n| Sin.eval(n)),
+            Cos => map(ns, |n| Cos.eval(n)),
+            Sqrt => map(ns, |n| Sqrt.eval(n)),
+            Exp => map(ns, |n| Exp.eval(n)),
+        }
     }
 }
 
-struct Batch {
-    entries: Vec<BatchEntry>,
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FBinary {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Atan2,
 }
 
-impl Batch {
-    fn each<F: Fn(&mut BatchEntry)>(&mut self, f: F) {
-        self.entries.iter_mut().for_each(f);
+impl FBinary {
+    /// Whether swapping the operands never changes the result.
+    pub fn is_commutative(self) -> bool {
+        matches!(self, FBinary::Add | FBinary::Mul)
     }
-    fn scale_256(&mut self, idx: usize) {
-        let mut minimums = [std::i32::MAX; 3];
-        let mut maximums = [std::i32::MIN; 3];
-        for entry in &self.entries {
-            let components = entry.get(idx).rgb();
-            for ch in 0..3 {
-                minimums[ch] = std::cmp::min(minimums[ch], components[ch]);
-                maximums[ch] = std::cmp::max(maximums[ch], components[ch]);
-            }
-        }
-        // We use f64s instead of f32s to prevent these subtractions from overflowing.
-        let ranges = [
-            maximums[0] as f64 - minimums[0] as f64,
-            maximums[1] as f64 - minimums[1] as f64,
-            maximums[2] as f64 - minimums[2] as f64,
-        ];
-        for entry in &mut self.entries {
-            let item = entry.get_mut(idx);
-            let mut components = item.rgb();
-            for ch in 0..3 {
-                let channel = &mut components[ch];
-                let range = ranges[ch];
-                let relative =
-                    if range == 0.0 { 0.5 }
-                    else { (*channel as f64 - minimums[ch] as f64) / range };
-                if relative < 0.0 || relative > 1.0 {
-                    println!("{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n\n",
-                        minimums, maximums, ranges, channel, relative);
-                }
-                assert!(0.0 <= relative && relative <= 1.0);
-                *channel = (255.0 * relative) as i32;
-            }
-            *item = components.into();
-        }
-    }
-}
-
-impl VExpr {
-    fn eval_on_batch(&self, batch: &mut Batch) {
-        use VExpr::*;
+    pub fn eval(self, a: f32, b: f32) -> f32 {
+        use FBinary::*;
         match self {
-            Pixel => batch.each(|e| {
-                e.push(e.pos.0);
-                e.push(e.pos.1);
-            }),
-            Swap(sub_e) => {
-                sub_e.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (a, b) = e.pop_2();
-                    e.push(b);
-                    e.push(a);
-                })
-            }
-            BinaryI(op_1, op_2, e_1, e_2) => {
-                e_1.eval_on_batch(batch);
-                e_2.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (a, b) = e.pop_2();
-                    let new_1 = a.eval_binary(b, *op_1);
-                    let new_2 = a.eval_binary(b, *op_2);
-                    e.push(new_1);
-                    e.push(new_2);
-                })
-            }
-            UnaryV(op, sub_e) => {
-                sub_e.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (n_1, n_2) = e.pop_2();
-                    let new_1 = n_1.eval_unary(*op);
-                    let new_2 = n_2.eval_unary(*op);
-                    e.push(new_1);
-                    e.push(new_2);
-                })
-            }
-            BinaryV(op, e_1, e_2) => {
-                e_1.eval_on_batch(batch);
-                e_2.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (b_1, b_2) = e.pop_2();
-                    let (a_1, a_2) = e.pop_2();
-                    let new_1 = a_1.eval_binary(b_1, *op);
-                    let new_2 = a_2.eval_binary(b_2, *op);
-                    e.push(new_1);
-                    e.push(new_2);
-                })
-            }
-            IfThenElseI(e_cond, e_then, e_else) => {
-                e_cond.eval_on_batch(batch);
-                e_then.eval_on_batch(batch);
-                e_else.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (else_1, else_2) = e.pop_2();
-                    let (then_1, then_2) = e.pop_2();
-                    let cond = e.pop();
-                    e.push(crate::utils::array_zip_3(
-                        |c, t, e| if c > 0 { t } else { e },
-                        cond.rgb(),
-                        then_1.rgb(),
-                        else_1.rgb(),
-                    ));
-                    e.push(crate::utils::array_zip_3(
-                        |c, t, e| if c > 0 { t } else { e },
-                        cond.rgb(),
-                        then_2.rgb(),
-                        else_2.rgb(),
-                    ));
-                })
-            }
-            IfThenElseV(e_cond, e_then, e_else) => {
-                e_cond.eval_on_batch(batch);
-                e_then.eval_on_batch(batch);
-                e_else.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (else_1, else_2) = e.pop_2();
-                    let (then_1, then_2) = e.pop_2();
-                    let (cond_1, cond_2) = e.pop_2();
-                    e.push(crate::utils::array_zip_3(
-                        |c, t, e| if c > 0 { t } else { e },
-                        cond_1.rgb(),
-                        then_1.rgb(),
-                        else_1.rgb(),
-                    ));
-                    e.push(crate::utils::array_zip_3(
-                        |c, t, e| if c > 0 { t } else { e },
-                        cond_2.rgb(),
-                        then_2.rgb(),
-                        else_2.rgb(),
-                    ));
-                })
-            }
+            Add => a + b,
+            Sub => a - b,
+            Mul => a * b,
+            Div => a / b,
+            Atan2 => a.atan2(b),
         }
     }
-}
-
-impl IExpr {
-    fn eval_on_batch(&self, batch: &mut Batch) {
-        use IExpr::*;
+    /// Replace each element of `a` with the result of applying the operator
+    /// to it and the corresponding element of `b`. Both hold the bit patterns
+    /// of floats.
+    pub fn eval_slices(self, a: &mut [i32], b: &[i32]) {
+        use FBinary::*;
+        fn zip(a: &mut [i32], b: &[i32], f: impl Fn(f32, f32) -> f32) {
+            a.iter_mut().zip(b).for_each(|(a, &b)| {
+                *a = f(f32::from_bits(*a as u32), f32::from_bits(b as u32)).to_bits() as i32
+            });
+        }
         match self {
-            Lit(n) => batch.each(|e| e.push(*n)),
-            Rgb([r, g, b]) => batch.each(|e| e.push([*r as i32, *g as i32, *b as i32])),
-            PixelX => batch.each(|e| e.push(e.pos.0)),
-            PixelY => batch.each(|e| e.push(e.pos.1)),
-            Channel => batch.each(|e| e.push([-1, 0, 1])),
-            Scale256(sub_e) => {
-                sub_e.eval_on_batch(batch);
-                batch.scale_256(0);
-            }
-            UnaryI(op, sub_e) => {
-                sub_e.eval_on_batch(batch);
-                batch.each(|e| {
-                    let new_val = e.pop().eval_unary(*op);
-                    e.push(new_val);
-                });
-            }
-            BinaryI(op, e_1, e_2) => {
-                e_1.eval_on_batch(batch);
-                e_2.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (a, b) = e.pop_2();
-                    let new_val = a.eval_binary(b, *op);
-                    e.push(new_val);
-                });
-            }
-            BinaryV(op, sub_e) => {
-                sub_e.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (a, b) = e.pop_2();
-                    let new_val = a.eval_binary(b, *op);
-                    e.push(new_val)
-                });
-            }
-            IfThenElseI(e_cond, e_then, e_else) => {
-                e_cond.eval_on_batch(batch);
-                e_then.eval_on_batch(batch);
-                e_else.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (val_then, val_else) = e.pop_2();
-                    let val_cond = e.pop();
-                    let new_val = crate::utils::array_zip_3(
-                        |c, t, e| if c > 0 { t } else { e },
-                        val_cond.rgb(),
-                        val_then.rgb(),
-                        val_else.rgb(),
-                    );
-                    e.push(new_val);
-                })
-            }
-            IfThenElseV(e_cond, e_case) => {
-                e_cond.eval_on_batch(batch);
-                e_case.eval_on_batch(batch);
-                batch.each(|e| {
-                    let (val_then, val_else) = e.pop_2();
-                    let val_cond = e.pop();
-                    let new_val = crate::utils::array_zip_3(
-                        |c, t, e| if c > 0 { t } else { e },
-                        val_cond.rgb(),
-                        val_then.rgb(),
-                        val_else.rgb(),
-                    );
-                    e.push(new_val);
-                })
-            }
+            Add => zip(a, b, |a, b| Add.eval(a, b)),
+            Sub => zip(a, b, |a, b| Sub.eval(a, b)),
+            Mul => zip(a, b, |a, b| Mul.eval(a, b)),
+            Div => zip(a, b, |a, b| Div.eval(a, b)),
+            Atan2 => zip(a, b, |a, b| Atan2.eval(a, b)),
         }
     }
-    pub fn eval_batch<PI>(&self, inputs: PI) -> impl Iterator<Item=Color>
-    where
-        PI: Iterator<Item=(i32, i32)>,
-    {
-        let mut batch = Batch {
-            entries: inputs.map(|pos| BatchEntry { pos, stack: Vec::new() }).collect()
-        };
+}
 
-        self.eval_on_batch(&mut batch);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        batch.entries.into_iter().map(|mut entry| {
-            assert_eq!(entry.len(), 1);
-            entry.pop().rgb()
-        })
+    #[test]
+    fn complex_ops_wrap_instead_of_overflowing() {
+        let z = (i32::MIN, i32::MIN);
+        assert_eq!(complex_mul(z, z), (0, 0));
+        assert_eq!(ComplexUnary::Square.eval(z), (0, 0));
+        assert_eq!(ComplexUnary::SquaredMagnitude.eval(z), (0, 0));
+        assert_eq!(ComplexUnary::Conj.eval(z), z);
     }
 }
@@ -1,35 +1,82 @@
 use rand::Rng;
 
-use crate::expr::{IExpr, VExpr, Unary, Binary};
+use crate::artwork::{ColorMode, Palette};
+use crate::arena::{ExprArena, IExprId, VExprId, FExprId, INode, VNode, FNode};
+use crate::dependency::Requirements;
+use crate::expr::{IExpr, Normalization, Unary, Binary, ComplexUnary, FUnary, FBinary};
+use crate::interval::Domain;
 use crate::utils::{self, weighted_choice};
 
+/// How many expressions `gen_image_expr` generates before settling for one
+/// that's boring or doesn't meet the requirements.
+const MAX_ATTEMPTS: usize = 32;
+
+/// An image whose channels each vary by less than this is too close to a
+/// single flat color to be worth showing.
+const MIN_CONTRAST: i64 = 8;
+
+/// Whether `e` is guaranteed to be nearly a flat color over `domain`.
+fn is_boring(e: &IExpr, domain: &Domain) -> bool {
+    e.dependencies().is_flat() || e.range(domain).iter().all(|range| range.span() < MIN_CONTRAST)
+}
+
+/// Rule out the choices at `indices` by giving them no weight.
+fn without<const N: usize>(mut weights: [f32; N], indices: &[usize]) -> [f32; N] {
+    for &i in indices {
+        weights[i] = 0.0;
+    }
+    weights
+}
+
 #[derive(Debug)]
 pub struct Parameters {
-    root_iexpr_weights: [f32; 3],
-    max_depth_iexpr_weights: [f32; 3],
-    min_depth_iexpr_weights: [f32; 6],
-    iexpr_weights: [f32; 9],
+    root_iexpr_weights: [f32; 6],
+    max_depth_iexpr_weights: [f32; 6],
+    min_depth_iexpr_weights: [f32; 12],
+    iexpr_weights: [f32; 18],
+
+    max_depth_vexpr_weights: [f32; 3],
+    min_depth_vexpr_weights: [f32; 8],
+    vexpr_weights: [f32; 11],
 
-    min_depth_vexpr_weights: [f32; 6],
-    vexpr_weights: [f32; 7],
+    max_depth_fexpr_weights: [f32; 2],
+    min_depth_fexpr_weights: [f32; 3],
+    fexpr_weights: [f32; 4],
 
-    unary_weights: [f32; 8],
-    binary_weights: [f32; 6],
+    unary_weights: [f32; 12],
+    binary_weights: [f32; 15],
+    complex_unary_weights: [f32; 3],
+    funary_weights: [f32; 4],
+    fbinary_weights: [f32; 5],
+
+    color_mode_weights: [f32; 4],
+    palette_weights: [f32; 5],
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Self {
-            root_iexpr_weights: [1.0; 3],
-            max_depth_iexpr_weights: [1.0; 3],
-            min_depth_iexpr_weights: [1.0; 6],
-            iexpr_weights: [1.0; 9],
+            root_iexpr_weights: [1.0; 6],
+            max_depth_iexpr_weights: [1.0; 6],
+            min_depth_iexpr_weights: [1.0; 12],
+            iexpr_weights: [1.0; 18],
+
+            max_depth_vexpr_weights: [1.0; 3],
+            min_depth_vexpr_weights: [1.0; 8],
+            vexpr_weights: [1.0; 11],
 
-            min_depth_vexpr_weights: [1.0; 6],
-            vexpr_weights: [1.0; 7],
+            max_depth_fexpr_weights: [1.0; 2],
+            min_depth_fexpr_weights: [1.0; 3],
+            fexpr_weights: [1.0; 4],
 
-            unary_weights: [1.0; 8],
-            binary_weights: [1.0; 6],
+            unary_weights: [1.0; 12],
+            binary_weights: [1.0; 15],
+            complex_unary_weights: [1.0; 3],
+            funary_weights: [1.0; 4],
+            fbinary_weights: [1.0; 5],
+
+            color_mode_weights: [1.0; 4],
+            palette_weights: [1.0; 5],
         }
     }
 }
@@ -41,11 +88,22 @@ impl Parameters {
         utils::perturb(rng, &mut self.min_depth_iexpr_weights);
         utils::perturb(rng, &mut self.iexpr_weights);
 
+        utils::perturb(rng, &mut self.max_depth_vexpr_weights);
         utils::perturb(rng, &mut self.min_depth_vexpr_weights);
         utils::perturb(rng, &mut self.vexpr_weights);
 
+        utils::perturb(rng, &mut self.max_depth_fexpr_weights);
+        utils::perturb(rng, &mut self.min_depth_fexpr_weights);
+        utils::perturb(rng, &mut self.fexpr_weights);
+
         utils::perturb(rng, &mut self.unary_weights);
         utils::perturb(rng, &mut self.binary_weights);
+        utils::perturb(rng, &mut self.complex_unary_weights);
+        utils::perturb(rng, &mut self.funary_weights);
+        utils::perturb(rng, &mut self.fbinary_weights);
+
+        utils::perturb(rng, &mut self.color_mode_weights);
+        utils::perturb(rng, &mut self.palette_weights);
     }
 
     pub fn mutate<R: Rng>(&self, other: &Self, rng: &mut R) -> Self {
@@ -71,6 +129,11 @@ impl Parameters {
             &other  .iexpr_weights,
             &mut new.iexpr_weights);
 
+        utils::mutate(rng,
+            &self   .max_depth_vexpr_weights,
+            &other  .max_depth_vexpr_weights,
+            &mut new.max_depth_vexpr_weights);
+
         utils::mutate(rng,
             &self   .min_depth_vexpr_weights,
             &other  .min_depth_vexpr_weights,
@@ -81,6 +144,21 @@ impl Parameters {
             &other  .vexpr_weights,
             &mut new.vexpr_weights);
 
+        utils::mutate(rng,
+            &self   .max_depth_fexpr_weights,
+            &other  .max_depth_fexpr_weights,
+            &mut new.max_depth_fexpr_weights);
+
+        utils::mutate(rng,
+            &self   .min_depth_fexpr_weights,
+            &other  .min_depth_fexpr_weights,
+            &mut new.min_depth_fexpr_weights);
+
+        utils::mutate(rng,
+            &self   .fexpr_weights,
+            &other  .fexpr_weights,
+            &mut new.fexpr_weights);
+
         utils::mutate(rng,
             &self   .unary_weights,
             &other  .unary_weights,
@@ -91,22 +169,68 @@ impl Parameters {
             &other  .binary_weights,
             &mut new.binary_weights);
 
+        utils::mutate(rng,
+            &self   .complex_unary_weights,
+            &other  .complex_unary_weights,
+            &mut new.complex_unary_weights);
+
+        utils::mutate(rng,
+            &self   .funary_weights,
+            &other  .funary_weights,
+            &mut new.funary_weights);
+
+        utils::mutate(rng,
+            &self   .fbinary_weights,
+            &other  .fbinary_weights,
+            &mut new.fbinary_weights);
+
+        utils::mutate(rng,
+            &self   .color_mode_weights,
+            &other  .color_mode_weights,
+            &mut new.color_mode_weights);
+
+        utils::mutate(rng,
+            &self   .palette_weights,
+            &other  .palette_weights,
+            &mut new.palette_weights);
+
         new.perturb(rng);
         new
     }
 
-    fn gen_literal<R: Rng>(rng: &mut R) -> IExpr {
+    fn gen_literal<R: Rng>(rng: &mut R) -> INode {
         if rng.gen() {
-            IExpr::Rgb([
+            INode::Rgb([
                 utils::small_positive(rng),
                 utils::small_positive(rng),
                 utils::small_positive(rng),
             ])
         } else {
-            IExpr::Lit(rng.gen())
+            INode::Lit(rng.gen())
         }
     }
 
+    /// Return a lattice spacing for noise. Small spacings just look like static.
+    fn gen_noise_scale<R: Rng>(rng: &mut R) -> u8 {
+        rng.gen_range(4, 65)
+    }
+
+    /// Return a distance to look for a neighboring pixel.
+    fn gen_offset<R: Rng>(rng: &mut R) -> i8 {
+        rng.gen_range(-8, 9)
+    }
+
+    /// Return a blur radius. Larger radii are slow and wash everything out.
+    fn gen_blur_radius<R: Rng>(rng: &mut R) -> u8 {
+        rng.gen_range(1, 5)
+    }
+
+    /// Return the most times to apply a loop body. Along with the small size
+    /// of generated loop bodies, this keeps loops within the default limits.
+    fn gen_iteration_count<R: Rng>(rng: &mut R) -> u8 {
+        rng.gen_range(8, 33)
+    }
+
     fn gen_unary<R: Rng>(&self, rng: &mut R) -> Unary {
         match weighted_choice(rng, &self.unary_weights) {
             0 => Unary::Square,
@@ -117,6 +241,10 @@ impl Parameters {
             5 => Unary::ModBy(utils::small_positive(rng)),
             6 => Unary::Mod256,
             7 => Unary::Clamp256,
+            8 => Unary::Popcount,
+            9 => Unary::Sqrt,
+            10 => Unary::Log2,
+            11 => Unary::Not,
             _ => unreachable!(),
         }
     }
@@ -129,132 +257,335 @@ impl Parameters {
             3 => Binary::BitAnd,
             4 => Binary::BitOr,
             5 => Binary::BitXor,
+            6 => Binary::Shl,
+            7 => Binary::Shr,
+            8 => Binary::RotL,
+            9 => Binary::Min,
+            10 => Binary::Max,
+            11 => Binary::Lt,
+            12 => Binary::Eq,
+            13 => Binary::Div,
+            14 => Binary::Mod,
             _ => unreachable!(),
         }
     }
 
-    fn gen_vexpr<R: Rng>(&self, rng: &mut R, max_depth: u8, min_depth: u8) -> VExpr {
-        if max_depth == 0 {
-            VExpr::Pixel
+    fn gen_complex_unary<R: Rng>(&self, rng: &mut R) -> ComplexUnary {
+        match weighted_choice(rng, &self.complex_unary_weights) {
+            0 => ComplexUnary::Conj,
+            1 => ComplexUnary::Square,
+            2 => ComplexUnary::SquaredMagnitude,
+            _ => unreachable!(),
+        }
+    }
+
+    fn gen_funary<R: Rng>(&self, rng: &mut R) -> FUnary {
+        match weighted_choice(rng, &self.funary_weights) {
+            0 => FUnary::Sin,
+            1 => FUnary::Cos,
+            2 => FUnary::Sqrt,
+            3 => FUnary::Exp,
+            _ => unreachable!(),
+        }
+    }
+
+    fn gen_fbinary<R: Rng>(&self, rng: &mut R) -> FBinary {
+        match weighted_choice(rng, &self.fbinary_weights) {
+            0 => FBinary::Add,
+            1 => FBinary::Sub,
+            2 => FBinary::Mul,
+            3 => FBinary::Div,
+            4 => FBinary::Atan2,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Return a float literal between -64 and 64. These are multiples of 1/16
+    /// so that they're displayed compactly.
+    fn gen_float_literal<R: Rng>(rng: &mut R) -> FNode {
+        FNode::Lit(rng.gen_range(-1024, 1024) as f32 / 16.0)
+    }
+
+    fn gen_fexpr<R: Rng>(&self, arena: &mut ExprArena, rng: &mut R, max_depth: u8, min_depth: u8, in_loop: bool) -> FExprId {
+        let node = if max_depth == 0 {
+            match weighted_choice(rng, &self.max_depth_fexpr_weights) {
+                0 => Self::gen_float_literal(rng),
+                1 => FNode::FromI(self.gen_iexpr(arena, rng, 0, 0, in_loop)),
+                _ => unreachable!()
+            }
+        } else if min_depth != 0 {
+            match weighted_choice(rng, &self.min_depth_fexpr_weights) {
+                0 => FNode::FromI(self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                1 => FNode::UnaryF(
+                    self.gen_funary(rng),
+                    self.gen_fexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                2 => FNode::BinaryF(
+                    self.gen_fbinary(rng),
+                    self.gen_fexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_fexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                _ => unreachable!()
+            }
+        } else {
+            match weighted_choice(rng, &self.fexpr_weights) {
+                0 => Self::gen_float_literal(rng),
+                1 => FNode::FromI(self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                2 => FNode::UnaryF(
+                    self.gen_funary(rng),
+                    self.gen_fexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                3 => FNode::BinaryF(
+                    self.gen_fbinary(rng),
+                    self.gen_fexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_fexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                _ => unreachable!()
+            }
+        };
+        arena.push_f(node)
+    }
+
+    fn gen_vexpr<R: Rng>(&self, arena: &mut ExprArena, rng: &mut R, max_depth: u8, min_depth: u8, in_loop: bool) -> VExprId {
+        let node = if max_depth == 0 {
+            // `Iterand` can only be used inside a loop body.
+            let weights = if in_loop { self.max_depth_vexpr_weights } else { without(self.max_depth_vexpr_weights, &[2]) };
+            match weighted_choice(rng, &weights) {
+                0 => VNode::Pixel,
+                1 => VNode::CenteredPixel,
+                2 => VNode::Iterand,
+                _ => unreachable!()
+            }
         } else if min_depth != 0 {
             match weighted_choice(rng, &self.min_depth_vexpr_weights) {
-                0 => VExpr::Swap(Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
-                1 => VExpr::BinaryI(
+                0 => VNode::Swap(self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                1 => VNode::BinaryI(
                     self.gen_binary(rng),
                     self.gen_binary(rng),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1))),
-                2 => VExpr::UnaryV(
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                2 => VNode::UnaryV(
                     self.gen_unary(rng),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
-                3 => VExpr::BinaryV(
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                3 => VNode::BinaryV(
                     self.gen_binary(rng),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
-                4 => VExpr::IfThenElseI(
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
-                5 => VExpr::IfThenElseV(
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                4 => VNode::IfThenElseI(
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                5 => VNode::IfThenElseV(
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                6 => VNode::UnaryC(
+                    self.gen_complex_unary(rng),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                7 => VNode::ComplexMul(
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
                 _ => unreachable!()
             }
         } else {
-            match weighted_choice(rng, &self.vexpr_weights) {
-                0 => VExpr::Pixel,
-                1 => VExpr::Swap(Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
-                2 => VExpr::BinaryI(
+            // `Iterand` can only be used inside a loop body.
+            let weights = if in_loop { self.vexpr_weights } else { without(self.vexpr_weights, &[8]) };
+            match weighted_choice(rng, &weights) {
+                0 => VNode::Pixel,
+                1 => VNode::Swap(self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                2 => VNode::BinaryI(
                     self.gen_binary(rng),
                     self.gen_binary(rng),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0))),
-                3 => VExpr::UnaryV(
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                3 => VNode::UnaryV(
                     self.gen_unary(rng),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
-                4 => VExpr::BinaryV(
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                4 => VNode::BinaryV(
                     self.gen_binary(rng),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
-                5 => VExpr::IfThenElseI(
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
-                6 => VExpr::IfThenElseV(
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0)),
-                        Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                5 => VNode::IfThenElseI(
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                6 => VNode::IfThenElseV(
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                7 => VNode::CenteredPixel,
+                8 => VNode::Iterand,
+                9 => VNode::UnaryC(
+                    self.gen_complex_unary(rng),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                10 => VNode::ComplexMul(
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
                 _ => unreachable!()
             }
-        }
+        };
+        arena.push_v(node)
     }
 
-    fn gen_iexpr<R: Rng>(&self, rng: &mut R, max_depth: u8, min_depth: u8) -> IExpr {
-        if max_depth == 0 {
+    fn gen_iexpr<R: Rng>(&self, arena: &mut ExprArena, rng: &mut R, max_depth: u8, min_depth: u8, in_loop: bool) -> IExprId {
+        let node = if max_depth == 0 {
             match weighted_choice(rng, &self.max_depth_iexpr_weights) {
                 0 => Self::gen_literal(rng),
-                1 => if rng.gen() { IExpr::PixelX } else { IExpr::PixelY }
-                2 => IExpr::Channel,
+                1 => if rng.gen() { INode::PixelX } else { INode::PixelY }
+                2 => INode::Channel,
+                3 => INode::Time,
+                4 => INode::Radius,
+                5 => INode::Angle,
                 _ => unreachable!()
             }
         } else if min_depth != 0 {
-            match weighted_choice(rng, &self.min_depth_iexpr_weights) {
-                0 => IExpr::Scale256(Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1))),
-                1 => IExpr::UnaryI(
+            // Barriers can't be evaluated inside loop bodies, and nested loops
+            // quickly get too slow, so rule them out there.
+            let weights = if in_loop {
+                without(self.min_depth_iexpr_weights, &[0, 8, 9, 10, 11])
+            } else {
+                self.min_depth_iexpr_weights
+            };
+            match weighted_choice(rng, &weights) {
+                0 => INode::Scale256(self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                1 => INode::UnaryI(
                     self.gen_unary(rng),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1))),
-                2 => IExpr::BinaryI(
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                2 => INode::BinaryI(
                     self.gen_binary(rng),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1))),
-                3 => IExpr::BinaryV(
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                3 => INode::BinaryV(
                     self.gen_binary(rng),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
-                4 => IExpr::IfThenElseI(
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1))),
-                5 => IExpr::IfThenElseV(
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, min_depth - 1)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, min_depth - 1))),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                4 => INode::IfThenElseI(
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                5 => INode::IfThenElseV(
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                6 => INode::FromF(self.gen_fexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                7 => INode::Noise(
+                    rng.gen(),
+                    Self::gen_noise_scale(rng),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                8 => INode::Offset(
+                    Self::gen_offset(rng),
+                    Self::gen_offset(rng),
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                9 => INode::Blur(
+                    Self::gen_blur_radius(rng),
+                    self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                10 => INode::Sobel(self.gen_iexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
+                11 => INode::Iterate(
+                    Self::gen_iteration_count(rng),
+                    self.gen_vexpr(arena, rng, std::cmp::min(max_depth - 1, 2), 0, true),
+                    self.gen_vexpr(arena, rng, max_depth - 1, min_depth - 1, in_loop)),
                 _ => unreachable!()
             }
         } else {
-            match weighted_choice(rng, &self.iexpr_weights) {
+            let weights = if in_loop { without(self.iexpr_weights, &[3, 14, 15, 16, 17]) } else { self.iexpr_weights };
+            match weighted_choice(rng, &weights) {
                 0 => Self::gen_literal(rng),
-                1 => if rng.gen() { IExpr::PixelX } else { IExpr::PixelY }
-                2 => IExpr::Channel,
-                3 => IExpr::Scale256(Box::new(self.gen_iexpr(rng, max_depth - 1, 0))),
-                4 => IExpr::UnaryI(
+                1 => if rng.gen() { INode::PixelX } else { INode::PixelY }
+                2 => INode::Channel,
+                3 => INode::Scale256(self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                4 => INode::UnaryI(
                     self.gen_unary(rng),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0))),
-                5 => IExpr::BinaryI(
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                5 => INode::BinaryI(
                     self.gen_binary(rng),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0))),
-                6 => IExpr::BinaryV(
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                6 => INode::BinaryV(
                     self.gen_binary(rng),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
-                7 => IExpr::IfThenElseI(
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0))),
-                8 => IExpr::IfThenElseV(
-                    Box::new(self.gen_iexpr(rng, max_depth - 1, 0)),
-                    Box::new(self.gen_vexpr(rng, max_depth - 1, 0))),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                7 => INode::IfThenElseI(
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                8 => INode::IfThenElseV(
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                9 => INode::Time,
+                10 => INode::FromF(self.gen_fexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                11 => INode::Radius,
+                12 => INode::Angle,
+                13 => INode::Noise(
+                    rng.gen(),
+                    Self::gen_noise_scale(rng),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                14 => INode::Offset(
+                    Self::gen_offset(rng),
+                    Self::gen_offset(rng),
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                15 => INode::Blur(
+                    Self::gen_blur_radius(rng),
+                    self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                16 => INode::Sobel(self.gen_iexpr(arena, rng, max_depth - 1, 0, in_loop)),
+                17 => INode::Iterate(
+                    Self::gen_iteration_count(rng),
+                    self.gen_vexpr(arena, rng, std::cmp::min(max_depth - 1, 2), 0, true),
+                    self.gen_vexpr(arena, rng, max_depth - 1, 0, in_loop)),
                 _ => unreachable!(),
             }
+        };
+        arena.push_i(node)
+    }
+
+    pub fn gen_color_mode<R: Rng>(&self, rng: &mut R) -> ColorMode {
+        match weighted_choice(rng, &self.color_mode_weights) {
+            0 => ColorMode::Rgb,
+            1 => ColorMode::Hsv,
+            2 => ColorMode::Hsl,
+            3 => ColorMode::Oklab,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Return a palette for an expression whose channels are all the same, or
+    /// `None` to leave it gray.
+    pub fn gen_palette<R: Rng>(&self, rng: &mut R) -> Option<Palette> {
+        match weighted_choice(rng, &self.palette_weights) {
+            0 => None,
+            1 => Some(Palette::Viridis),
+            2 => Some(Palette::Magma),
+            3 => Some(Palette::Plasma),
+            4 => {
+                // Stops at both ends, plus up to two in between.
+                let mut positions = vec![0, 255];
+                positions.extend((0..rng.gen_range(0, 3)).map(|_| rng.gen_range(1, 255)));
+                positions.sort_unstable();
+                positions.dedup();
+                Some(Palette::Stops(positions.into_iter().map(|position| (position, rng.gen())).collect()))
+            }
+            _ => unreachable!(),
         }
     }
 
     pub fn gen_expr<R: Rng>(&self, rng: &mut R, max_depth: u8, min_depth: u8) -> IExpr {
-        let interior = self.gen_iexpr(rng, max_depth, min_depth);
-        match weighted_choice(rng, &self.root_iexpr_weights) {
-            0 => IExpr::Scale256(Box::new(interior)),
-            1 => IExpr::UnaryI(Unary::Mod256, Box::new(interior)),
-            2 => IExpr::UnaryI(Unary::Clamp256, Box::new(interior)),
+        let mut arena = ExprArena::new();
+        let interior = self.gen_iexpr(&mut arena, rng, max_depth, min_depth, false);
+        let root = arena.push_i(match weighted_choice(rng, &self.root_iexpr_weights) {
+            0 => INode::Scale256(interior),
+            1 => INode::UnaryI(Unary::Mod256, interior),
+            2 => INode::UnaryI(Unary::Clamp256, interior),
+            3 => INode::Normalize(Normalization::Percentile(rng.gen_range(1, 11)), interior),
+            4 => INode::Normalize(Normalization::Equalize, interior),
+            5 => INode::Normalize(Normalization::Rank, interior),
             _ => unreachable!(),
+        });
+        arena.iexpr(root)
+    }
+
+    /// Generate a simplified expression, trying again a number of times if
+    /// static analysis shows that it's boring over `domain` or that it
+    /// doesn't meet `requirements`.
+    pub fn gen_image_expr<R: Rng>(&self, rng: &mut R, max_depth: u8, min_depth: u8, domain: &Domain, requirements: &Requirements) -> IExpr {
+        let mut expr = self.gen_expr(rng, max_depth, min_depth).simplify();
+        for _ in 1..MAX_ATTEMPTS {
+            if !is_boring(&expr, domain) && requirements.accepts(expr.dependencies()) {
+                break;
+            }
+            expr = self.gen_expr(rng, max_depth, min_depth).simplify();
         }
+        expr
     }
 }
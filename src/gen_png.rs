@@ -3,7 +3,10 @@ use png::Encoder;
 use std::io::Write;
 use std::convert::TryInto;
 
-use crate::expr::IExpr;
+use crate::artwork::{Artwork, ColorMode, Palette};
+use crate::expr::{IExpr, Color};
+use crate::program::{EvalError, Layout};
+use crate::viewport::Viewport;
 
 /// Encode a buffer of pixel data as a PNG and write it to `w`.
 pub fn write_rgba_image_data(w: impl Write, width: u32, height: u32, data: &[u8]) {
@@ -14,33 +17,227 @@ pub fn write_rgba_image_data(w: impl Write, width: u32, height: u32, data: &[u8]
         .write_image_data(data).unwrap();
 }
 
-impl IExpr {
-    pub fn write_image_data(&self, w: impl Write, width: u32, height: u32, scale: u32) {
-        let image_width = scale * width;
-        let image_height = scale * height;
-
-        let mut data        = Vec::with_capacity((image_width * image_height * 4) as usize);
-        let mut current_row = Vec::with_capacity((image_width * 4) as usize);
-
-        let batch = (0..height).flat_map(|y| (0..width).map(move |x| (x as i32, y as i32)));
-        let colors = self.eval_batch(batch);
-
-        for (i, [r, g, b]) in colors.enumerate() {
-            for _ in 0..scale {
-                current_row.extend(&[
-                    r.try_into().unwrap(),
-                    g.try_into().unwrap(),
-                    b.try_into().unwrap(),
-                    0xff]);
+/// Compress one frame of pixel data the way it's stored in an `IDAT` or
+/// `fdAT` chunk, with each row unfiltered.
+fn compress_frame(width: u32, data: &[u8]) -> Vec<u8> {
+    let row_len = (width * 4) as usize;
+    let mut filtered = Vec::with_capacity(data.len() + data.len() / row_len);
+    for row in data.chunks(row_len) {
+        filtered.push(0);
+        filtered.extend(row);
+    }
+    deflate::deflate_bytes_zlib(&filtered)
+}
+
+/// Encode several buffers of pixel data as the frames of an animated PNG which
+/// loops forever, showing each frame for `delay_ms` milliseconds.
+pub fn write_rgba_animation_data(w: impl Write, width: u32, height: u32, frames: &[Vec<u8>], delay_ms: u16) {
+    let mut encoder = Encoder::new(w, width, height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+
+    let mut actl = Vec::with_capacity(8);
+    actl.extend(&(frames.len() as u32).to_be_bytes());
+    actl.extend(&0u32.to_be_bytes());
+    writer.write_chunk(png::chunk::acTL, &actl).unwrap();
+
+    // `fcTL` and `fdAT` chunks share a single sequence of numbers.
+    let mut sequence_number = 0u32;
+    for (i, frame) in frames.iter().enumerate() {
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend(&sequence_number.to_be_bytes());
+        fctl.extend(&width.to_be_bytes());
+        fctl.extend(&height.to_be_bytes());
+        fctl.extend(&0u32.to_be_bytes());
+        fctl.extend(&0u32.to_be_bytes());
+        fctl.extend(&delay_ms.to_be_bytes());
+        fctl.extend(&1000u16.to_be_bytes());
+        // Leave the frame in place and overwrite it entirely with the next one.
+        fctl.extend(&[0, 0]);
+        writer.write_chunk(png::chunk::fcTL, &fctl).unwrap();
+        sequence_number += 1;
+
+        let compressed = compress_frame(width, frame);
+        if i == 0 {
+            // The first frame doubles as the still image shown by viewers
+            // that don't support animation.
+            writer.write_chunk(png::chunk::IDAT, &compressed).unwrap();
+        } else {
+            let mut fdat = Vec::with_capacity(4 + compressed.len());
+            fdat.extend(&sequence_number.to_be_bytes());
+            fdat.extend(&compressed);
+            writer.write_chunk(png::chunk::fdAT, &fdat).unwrap();
+            sequence_number += 1;
+        }
+    }
+}
+
+/// Find the red, green and blue components of a color with the given hue and
+/// chroma, before lightening it by adding the same amount to each.
+fn hue_to_rgb(hue: u8, chroma: f32) -> [f32; 3] {
+    let sector = hue as f32 / 256.0 * 6.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    match sector as u32 {
+        0 => [chroma, x, 0.0],
+        1 => [x, chroma, 0.0],
+        2 => [0.0, chroma, x],
+        3 => [0.0, x, chroma],
+        4 => [x, 0.0, chroma],
+        _ => [chroma, 0.0, x],
+    }
+}
+
+/// Convert a linear sRGB component to a gamma-encoded one.
+fn gamma_encode(n: f32) -> f32 {
+    if n <= 0.003_130_8 {
+        12.92 * n
+    } else {
+        1.055 * n.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Read a color in the given mode and convert it to sRGB. Colors outside of
+/// the sRGB gamut are clipped.
+fn to_rgb(mode: ColorMode, color: [u8; 3]) -> [u8; 3] {
+    let [c_1, c_2, c_3] = color.map(|n| n as f32 / 255.0);
+    let rgb = match mode {
+        ColorMode::Rgb => return color,
+        ColorMode::Hsv => {
+            let chroma = c_3 * c_2;
+            hue_to_rgb(color[0], chroma).map(|n| n + c_3 - chroma)
+        }
+        ColorMode::Hsl => {
+            let chroma = (1.0 - (2.0 * c_3 - 1.0).abs()) * c_2;
+            hue_to_rgb(color[0], chroma).map(|n| n + c_3 - chroma / 2.0)
+        }
+        ColorMode::Oklab => {
+            let (l, a, b) = (c_1, 0.8 * c_2 - 0.4, 0.8 * c_3 - 0.4);
+            let l_ = (l + 0.396_337_8 * a + 0.215_803_76 * b).powi(3);
+            let m_ = (l - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+            let s_ = (l - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+            [
+                4.076_741_7 * l_ - 3.307_711_6 * m_ + 0.230_969_94 * s_,
+                -1.268_438 * l_ + 2.609_757_4 * m_ - 0.341_319_38 * s_,
+                -0.004_196_086_3 * l_ - 0.703_418_6 * m_ + 1.707_614_7 * s_,
+            ].map(gamma_encode)
+        }
+    };
+    rgb.map(|n| (255.0 * n.clamp(0.0, 1.0)).round() as u8)
+}
+
+// These are sampled from the matplotlib colormaps of the same names.
+const VIRIDIS: [(u8, [u8; 3]); 9] = [
+    (0, [0x44, 0x01, 0x54]), (32, [0x47, 0x2d, 0x7b]), (64, [0x3b, 0x52, 0x8b]),
+    (96, [0x2c, 0x72, 0x8e]), (128, [0x21, 0x91, 0x8c]), (159, [0x28, 0xae, 0x80]),
+    (191, [0x5e, 0xc9, 0x62]), (223, [0xad, 0xdc, 0x30]), (255, [0xfd, 0xe7, 0x25]),
+];
+const MAGMA: [(u8, [u8; 3]); 9] = [
+    (0, [0x00, 0x00, 0x04]), (32, [0x1c, 0x10, 0x44]), (64, [0x4f, 0x12, 0x7b]),
+    (96, [0x81, 0x25, 0x81]), (128, [0xb5, 0x36, 0x7a]), (159, [0xe5, 0x50, 0x64]),
+    (191, [0xfb, 0x87, 0x61]), (223, [0xfe, 0xc2, 0x87]), (255, [0xfc, 0xfd, 0xbf]),
+];
+const PLASMA: [(u8, [u8; 3]); 9] = [
+    (0, [0x0d, 0x08, 0x87]), (32, [0x4c, 0x02, 0xa1]), (64, [0x7e, 0x03, 0xa8]),
+    (96, [0xa9, 0x23, 0x95]), (128, [0xcc, 0x47, 0x78]), (159, [0xe5, 0x6b, 0x5d]),
+    (191, [0xf8, 0x95, 0x40]), (223, [0xfd, 0xc5, 0x27]), (255, [0xf0, 0xf9, 0x21]),
+];
+
+/// Look up the color of `n` in the palette. A palette without stops is black.
+fn palette_color(palette: &Palette, n: u8) -> [u8; 3] {
+    let stops: &[(u8, [u8; 3])] = match palette {
+        Palette::Viridis => &VIRIDIS,
+        Palette::Magma => &MAGMA,
+        Palette::Plasma => &PLASMA,
+        Palette::Stops(stops) => stops,
+    };
+    match stops.iter().position(|&(position, _)| position >= n) {
+        None => stops.last().map_or([0, 0, 0], |&(_, color)| color),
+        Some(0) => stops[0].1,
+        Some(i) => {
+            let ((start, from), (end, to)) = (stops[i - 1], stops[i]);
+            let t = (n - start) as u32;
+            let span = (end - start) as u32;
+            let mut color = [0; 3];
+            for ch in 0..3 {
+                color[ch] = ((from[ch] as u32 * (span - t) + to[ch] as u32 * t) / span) as u8;
             }
-            if (i + 1) % (width as usize) == 0 {
-                for _ in 0..scale {
-                    data.extend(&current_row);
-                }
-                current_row.clear();
+            color
+        }
+    }
+}
+
+impl IExpr {
+    /// Evaluate the expression at every pixel of frames `0..frames` of the
+    /// viewport, with horizontal bands of each frame spread across up to
+    /// `threads` threads. `Scale256` normalizes over every frame at once so
+    /// that the colors stay consistent throughout the animation.
+    fn eval_frames(&self, viewport: &Viewport, frames: u32, threads: usize) -> Result<Vec<Vec<Color>>, EvalError> {
+        let (width, height) = (viewport.width, viewport.height);
+        let band_height = std::cmp::max(1, (height as usize).div_ceil(std::cmp::max(1, threads)));
+        let bands = (0..frames as i32)
+            .flat_map(|t| (0..height).step_by(band_height).map(move |top| (t, top)))
+            .map(|(t, top)| {
+                let bottom = std::cmp::min(top + band_height as u32, height);
+                (top..bottom)
+                    .flat_map(|y| (0..width).map(move |x| {
+                        let (x, y) = viewport.coords(x, y);
+                        (x, y, t)
+                    }))
+                    .collect()
+            })
+            .collect();
+        let layout = Layout { center: viewport.center, width, height };
+        let colors = self.compile().eval_tiles(bands, layout, threads)?.into_iter().flatten().collect::<Vec<_>>();
+        Ok(colors.chunks((width * height) as usize).map(<[Color]>::to_vec).collect())
+    }
+}
+
+impl Artwork {
+    /// Convert colors to RGBA pixel data using the color mode or palette.
+    fn rgba_data(&self, colors: &[Color]) -> Result<Vec<u8>, EvalError> {
+        let mut data = Vec::with_capacity(colors.len() * 4);
+        let component = |n: i32| n.try_into().map_err(|_| EvalError::InvalidColor(n));
+        for &[r, g, b] in colors {
+            match &self.palette {
+                Some(palette) => data.extend(&palette_color(palette, component(r)?)),
+                None => data.extend(&to_rgb(self.color_mode, [component(r)?, component(g)?, component(b)?])),
             }
+            data.push(0xff);
         }
+        Ok(data)
+    }
+
+    /// Render the viewport of the artwork as a PNG, evaluating horizontal
+    /// bands of the image on up to `threads` threads.
+    pub fn write_image_data(&self, w: impl Write, viewport: &Viewport, threads: usize) -> Result<(), EvalError> {
+        let colors = self.expr.eval_frames(viewport, 1, threads)?.remove(0);
+        write_rgba_image_data(w, viewport.width, viewport.height, &self.rgba_data(&colors)?);
+        Ok(())
+    }
+
+    /// Render frames `0..frames` of the viewport of the artwork as an
+    /// animated PNG.
+    pub fn write_animation_data(&self, w: impl Write, viewport: &Viewport, frames: u32, delay_ms: u16, threads: usize) -> Result<(), EvalError> {
+        let frames = self.expr.eval_frames(viewport, frames, threads)?.iter()
+            .map(|colors| self.rgba_data(colors))
+            .collect::<Result<Vec<_>, _>>()?;
+        write_rgba_animation_data(w, viewport.width, viewport.height, &frames, delay_ms);
+        Ok(())
+    }
+}
 
-        write_rgba_image_data(w, image_width, image_height, &data)
+/// Check that two expressions render the same frames over a couple of
+/// viewports, for testing passes that shouldn't change what an expression
+/// means.
+#[cfg(test)]
+pub fn assert_same_pixels(before: &IExpr, after: &IExpr) {
+    let viewports = [
+        Viewport::new(256, 256).resize(24, 24),
+        Viewport::new(24, 24).zoom(0.25).look_at((-300.0, 500.0)),
+    ];
+    for viewport in &viewports {
+        let render = |e: &IExpr| e.eval_frames(viewport, 2, 1).ok();
+        assert!(render(before) == render(after), "`{}` renders differently from `{}`", after, before);
     }
 }
@@ -0,0 +1,595 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::expr::{IExpr, VExpr, FExpr, Unary, Binary, ComplexUnary, ESCAPE_BOUND, FIXED_POINT_BITS};
+use crate::path::{Path, write_path};
+use crate::utils::clamp;
+use crate::viewport::Viewport;
+
+/// The integers from `min` to `max` inclusive. Intervals are never empty.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Interval {
+    pub min: i32,
+    pub max: i32,
+}
+
+/// An interval for each channel.
+pub type ColorRange = [Interval; 3];
+
+/// An interval for each channel of both elements of a pair.
+type PairRange = (ColorRange, ColorRange);
+
+impl Interval {
+    pub const FULL: Self = Self { min: i32::MIN, max: i32::MAX };
+    /// The values that a color component can take.
+    pub const COMPONENT: Self = Self { min: 0, max: 255 };
+
+    pub fn new(min: i32, max: i32) -> Self {
+        debug_assert!(min <= max);
+        Self { min, max }
+    }
+    pub fn point(n: i32) -> Self {
+        Self { min: n, max: n }
+    }
+    pub fn is_point(self) -> bool {
+        self.min == self.max
+    }
+    /// Whether every value in `self` is also in `other`.
+    pub fn is_within(self, other: Self) -> bool {
+        other.min <= self.min && self.max <= other.max
+    }
+    /// How far apart the smallest and largest values are.
+    pub fn span(self) -> i64 {
+        self.max as i64 - self.min as i64
+    }
+    /// The smallest interval containing both intervals.
+    pub fn union(self, other: Self) -> Self {
+        Self::new(std::cmp::min(self.min, other.min), std::cmp::max(self.max, other.max))
+    }
+    /// The interval from `min` to `max`, or every integer if either end
+    /// doesn't fit in 32 bits, since wrapping could then give any value.
+    fn wrapping(min: i128, max: i128) -> Self {
+        if i32::MIN as i128 <= min && max <= i32::MAX as i128 {
+            Self::new(min as i32, max as i32)
+        } else {
+            Self::FULL
+        }
+    }
+    /// The smallest interval containing every one of `ns`, which mustn't be
+    /// empty, with the same treatment of overflow as `wrapping`.
+    fn hull(ns: impl IntoIterator<Item = i128>) -> Self {
+        let (min, max) = ns.into_iter().fold((i128::MAX, i128::MIN), |(min, max), n| {
+            (std::cmp::min(min, n), std::cmp::max(max, n))
+        });
+        Self::wrapping(min, max)
+    }
+    /// Apply `f` to every combination of the ends of `self` and `other`.
+    fn corners(self, other: Self, f: impl Fn(i128, i128) -> i128) -> [i128; 4] {
+        let (a, b) = (self, other);
+        [(a.min, b.min), (a.min, b.max), (a.max, b.min), (a.max, b.max)]
+            .map(|(a, b)| f(a as i128, b as i128))
+    }
+    /// The smallest and largest absolute values in the interval.
+    fn abs_bounds(self) -> (i128, i128) {
+        let (min, max) = (self.min as i128, self.max as i128);
+        if min >= 0 {
+            (min, max)
+        } else if max <= 0 {
+            (-max, -min)
+        } else {
+            (0, std::cmp::max(-min, max))
+        }
+    }
+    /// The parts of the interval below and above 0, if there are any.
+    fn nonzero_parts(self) -> Vec<Self> {
+        let mut parts = Vec::new();
+        if self.min < 0 {
+            parts.push(Self::new(self.min, std::cmp::min(self.max, -1)));
+        }
+        if self.max > 0 {
+            parts.push(Self::new(std::cmp::max(self.min, 1), self.max));
+        }
+        parts
+    }
+    fn contains(self, n: i32) -> bool {
+        self.min <= n && n <= self.max
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.is_point() {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{} to {}", self.min, self.max)
+        }
+    }
+}
+
+/// The smallest and largest value of the square of an element of `a`.
+fn square_bounds(a: Interval) -> (i128, i128) {
+    let (min, max) = a.abs_bounds();
+    (min * min, max * max)
+}
+
+/// The smallest and largest product of an element of `a` and one of `b`.
+fn product_bounds(a: Interval, b: Interval) -> (i128, i128) {
+    let products = a.corners(b, |a, b| a * b);
+    (*products.iter().min().unwrap(), *products.iter().max().unwrap())
+}
+
+/// Convert the bounds of a sum of products back to fixed point, as complex
+/// multiplication does.
+fn fixed_point(min: i128, max: i128) -> Interval {
+    Interval::wrapping(min >> FIXED_POINT_BITS, max >> FIXED_POINT_BITS)
+}
+
+/// The Euclidean remainders of the elements of `a` divided by `d`.
+fn remainders(a: Interval, d: i32) -> Interval {
+    if a.min.div_euclid(d) == a.max.div_euclid(d) {
+        Interval::new(a.min.rem_euclid(d), a.max.rem_euclid(d))
+    } else {
+        Interval::new(0, d - 1)
+    }
+}
+
+/// The smallest number of the form 2^k - 1 that is at least `n`, which
+/// mustn't be negative.
+fn mask(n: i32) -> i32 {
+    u32::MAX.checked_shr(n.leading_zeros()).unwrap_or(0) as i32
+}
+
+/// The values of `op` applied to the elements of `a`.
+fn unary(op: Unary, a: Interval) -> Interval {
+    use Unary::*;
+    if a.is_point() {
+        return Interval::point(op.eval(a.min));
+    }
+    match op {
+        Square => {
+            let (min, max) = square_bounds(a);
+            Interval::wrapping(min, max)
+        }
+        Cube => Interval::wrapping((a.min as i128).pow(3), (a.max as i128).pow(3)),
+        Abs => {
+            let (min, max) = a.abs_bounds();
+            Interval::wrapping(min, max)
+        }
+        Neg => Interval::wrapping(-(a.max as i128), -(a.min as i128)),
+        DivBy(0) | ModBy(0) => Interval::FULL,
+        DivBy(d) => Interval::new(a.min.div_euclid(d as i32), a.max.div_euclid(d as i32)),
+        ModBy(d) => remainders(a, d as i32),
+        Mod256 => remainders(a, 256),
+        Clamp256 => Interval::new(clamp(0, 255, a.min), clamp(0, 255, a.max)),
+        Popcount => Interval::new(0, 32),
+        // Both of these are monotonic on either side of 0.
+        Sqrt => Interval::new(op.eval(a.min), op.eval(a.max)),
+        Log2 => {
+            if a.min >= 0 {
+                Interval::new(op.eval(a.min), op.eval(a.max))
+            } else if a.max <= 0 {
+                Interval::new(op.eval(a.max), op.eval(a.min))
+            } else {
+                Interval::new(-1, std::cmp::max(op.eval(a.min), op.eval(a.max)))
+            }
+        }
+        Not => Interval::new(!a.max, !a.min),
+    }
+}
+
+/// The values of `op` applied to an element of `a` and one of `b`.
+fn binary(op: Binary, a: Interval, b: Interval) -> Interval {
+    use Binary::*;
+    if a.is_point() && b.is_point() {
+        return Interval::point(op.eval(a.min, b.min));
+    }
+    let shift_in_range = 0 <= b.min && b.max < 32;
+    match op {
+        Add => Interval::wrapping(a.min as i128 + b.min as i128, a.max as i128 + b.max as i128),
+        Sub => Interval::wrapping(a.min as i128 - b.max as i128, a.max as i128 - b.min as i128),
+        Mul => Interval::hull(a.corners(b, |a, b| a * b)),
+        BitAnd => match (a.min >= 0, b.min >= 0) {
+            (true, true) => Interval::new(0, std::cmp::min(a.max, b.max)),
+            (true, false) => Interval::new(0, a.max),
+            (false, true) => Interval::new(0, b.max),
+            (false, false) => Interval::FULL,
+        },
+        BitOr if a.min >= 0 && b.min >= 0 =>
+            Interval::new(std::cmp::max(a.min, b.min), mask(std::cmp::max(a.max, b.max))),
+        BitXor if a.min >= 0 && b.min >= 0 => Interval::new(0, mask(std::cmp::max(a.max, b.max))),
+        // Shifts are monotonic in each operand, so the extremes are at the
+        // corners.
+        Shl if shift_in_range => Interval::hull(a.corners(b, |a, b| a << b)),
+        Shr if shift_in_range => Interval::hull(a.corners(b, |a, b| a >> b)),
+        // Shifting right moves a value towards 0 or -1.
+        Shr => Interval::new(std::cmp::min(a.min, 0), std::cmp::max(a.max, 0)),
+        Min => Interval::new(std::cmp::min(a.min, b.min), std::cmp::min(a.max, b.max)),
+        Max => Interval::new(std::cmp::max(a.min, b.min), std::cmp::max(a.max, b.max)),
+        Lt if a.max < b.min => Interval::point(1),
+        Lt if a.min >= b.max => Interval::point(0),
+        Eq if a.max < b.min || b.max < a.min => Interval::point(0),
+        Lt | Eq => Interval::new(0, 1),
+        Div | Mod => {
+            // Dividing by 0 gives 0, and on either side of 0 the quotient is
+            // monotonic in each operand.
+            let zero = if b.contains(0) { Some(Interval::point(0)) } else { None };
+            b.nonzero_parts().into_iter()
+                .map(|b| match op {
+                    Div => Interval::hull(a.corners(b, i128::div_euclid)),
+                    _ => {
+                        let (min_divisor, max_divisor) = b.abs_bounds();
+                        if a.min >= 0 && (a.max as i128) < min_divisor {
+                            a
+                        } else {
+                            Interval::wrapping(0, max_divisor - 1)
+                        }
+                    }
+                })
+                .chain(zero)
+                .reduce(Interval::union)
+                .unwrap()
+        }
+        BitOr | BitXor | Shl | RotL => Interval::FULL,
+    }
+}
+
+/// The values of `op` applied to a complex number with its real part in `re`
+/// and its imaginary part in `im`.
+fn complex_unary(op: ComplexUnary, (re, im): (Interval, Interval)) -> (Interval, Interval) {
+    match op {
+        ComplexUnary::Conj => (re, unary(Unary::Neg, im)),
+        ComplexUnary::Square => {
+            let ((re_min, re_max), (im_min, im_max)) = (square_bounds(re), square_bounds(im));
+            let (min, max) = product_bounds(re, im);
+            (fixed_point(re_min - im_max, re_max - im_min), fixed_point(2 * min, 2 * max))
+        }
+        ComplexUnary::SquaredMagnitude => {
+            let ((re_min, re_max), (im_min, im_max)) = (square_bounds(re), square_bounds(im));
+            (fixed_point(re_min + im_min, re_max + im_max), Interval::point(0))
+        }
+    }
+}
+
+/// The values of the product of complex numbers with parts in the given
+/// intervals.
+fn complex_mul((a, b): (Interval, Interval), (c, d): (Interval, Interval)) -> (Interval, Interval) {
+    let (ac, bd, ad, bc) = (product_bounds(a, c), product_bounds(b, d), product_bounds(a, d), product_bounds(b, c));
+    (fixed_point(ac.0 - bd.1, ac.1 - bd.0), fixed_point(ad.0 + bc.0, ad.1 + bc.1))
+}
+
+/// The values of `Sobel` over an image whose values are in `a`.
+fn sobel(a: Interval) -> Interval {
+    // Each component of the gradient is a difference of sums with weights
+    // adding up to 4, so the magnitude is at most 4 * sqrt(2) times the span.
+    Interval::new(0, std::cmp::min(6 * a.span(), i32::MAX as i64) as i32)
+}
+
+/// The values of `Radius` at the given coordinates relative to the center.
+fn radius((x, y): (Interval, Interval)) -> Interval {
+    let ((x_min, x_max), (y_min, y_max)) = (x.abs_bounds(), y.abs_bounds());
+    let hypot = |x: i128, y: i128| (x as f64).hypot(y as f64) as i32;
+    Interval::new(hypot(x_min, y_min), hypot(x_max, y_max))
+}
+
+/// Whether a condition with values in `range` holds in every channel
+/// (`Some(true)`), in no channel (`Some(false)`), or could go either way.
+pub fn condition(range: &ColorRange) -> Option<bool> {
+    if range.iter().all(|a| a.min > 0) {
+        Some(true)
+    } else if range.iter().all(|a| a.max <= 0) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Whether every channel in `range` is a valid color component.
+pub fn is_color(range: &ColorRange) -> bool {
+    range.iter().all(|a| a.is_within(Interval::COMPONENT))
+}
+
+/// The values of selecting between `then` and `else_` based on `cond`, in
+/// each channel.
+fn select(cond: &ColorRange, then: ColorRange, else_: ColorRange) -> ColorRange {
+    [0, 1, 2].map(|ch| {
+        if cond[ch].min > 0 {
+            then[ch]
+        } else if cond[ch].max <= 0 {
+            else_[ch]
+        } else {
+            then[ch].union(else_[ch])
+        }
+    })
+}
+
+fn zip_channels(a: ColorRange, b: ColorRange, f: impl Fn(Interval, Interval) -> Interval) -> ColorRange {
+    [0, 1, 2].map(|ch| f(a[ch], b[ch]))
+}
+
+/// Apply `f` to each channel of a pair read as a complex number.
+fn map_complex(a: PairRange, f: impl Fn((Interval, Interval)) -> (Interval, Interval)) -> PairRange {
+    let channels = [0, 1, 2].map(|ch| f((a.0[ch], a.1[ch])));
+    (channels.map(|(re, _)| re), channels.map(|(_, im)| im))
+}
+
+/// The inputs that an expression is evaluated at.
+#[derive(Clone, Copy, Debug)]
+pub struct Domain {
+    pub x: Interval,
+    pub y: Interval,
+    pub time: Interval,
+    /// The coordinates relative to the point that polar coordinates are
+    /// measured from.
+    pub centered: (Interval, Interval),
+}
+
+impl Domain {
+    /// Every input, for facts that hold wherever an expression is rendered.
+    pub const ANY: Self = Self {
+        x: Interval::FULL,
+        y: Interval::FULL,
+        time: Interval { min: 0, max: i32::MAX },
+        centered: (Interval::FULL, Interval::FULL),
+    };
+
+    /// The inputs of frames `0..frames` of the viewport.
+    pub fn new(viewport: &Viewport, frames: u32) -> Self {
+        let (left, top) = viewport.coords(0, 0);
+        let (right, bottom) = viewport.coords(viewport.width.saturating_sub(1), viewport.height.saturating_sub(1));
+        let (x, y) = (Interval::new(left, right), Interval::new(top, bottom));
+        let (center_x, center_y) = viewport.center;
+        Self {
+            x,
+            y,
+            time: Interval::new(0, frames.saturating_sub(1) as i32),
+            centered: (binary(Binary::Sub, x, Interval::point(center_x)), binary(Binary::Sub, y, Interval::point(center_y))),
+        }
+    }
+}
+
+/// What range analysis found out about an expression without evaluating it.
+#[derive(Debug)]
+pub struct Ranges {
+    /// A range that each channel of the result is guaranteed to be within.
+    pub output: ColorRange,
+    /// The paths to `IfThenElseI` nodes whose condition always holds (`true`)
+    /// or never holds (`false`) in every channel.
+    pub constant_conditions: Vec<(Path, bool)>,
+    /// The paths to `Clamp256` and `Mod256` nodes whose subexpression is
+    /// already between 0 and 255.
+    pub redundant_wrappers: Vec<Path>,
+}
+
+impl Display for Ranges {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let [r, g, b] = self.output;
+        if r == g && g == b {
+            writeln!(f, "every channel: {}", r)?;
+        } else {
+            writeln!(f, "red: {}, green: {}, blue: {}", r, g, b)?;
+        }
+        f.write_str("constant conditions: ")?;
+        if self.constant_conditions.is_empty() {
+            f.write_str("none")?;
+        }
+        for (i, (path, holds)) in self.constant_conditions.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write_path(f, path)?;
+            f.write_str(if *holds { " is always true" } else { " is always false" })?;
+        }
+        f.write_str("\nredundant wrappers: ")?;
+        if self.redundant_wrappers.is_empty() {
+            f.write_str("none")?;
+        }
+        for (i, path) in self.redundant_wrappers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write_path(f, path)?;
+        }
+        Ok(())
+    }
+}
+
+struct Analyzer<'a> {
+    domain: &'a Domain,
+    /// The ranges of the variables in scope, innermost last.
+    vars: Vec<ColorRange>,
+    /// The ranges of the pairs that the enclosing loop bodies are applied to,
+    /// innermost last.
+    iterands: Vec<PairRange>,
+    /// The path to the node being analyzed.
+    path: Path,
+    constant_conditions: Vec<(Path, bool)>,
+    redundant_wrappers: Vec<Path>,
+}
+
+impl Analyzer<'_> {
+    // These analyze the subexpression at `position` of the current node.
+    fn i(&mut self, position: usize, e: &IExpr) -> ColorRange {
+        self.path.push(position);
+        let range = self.iexpr(e);
+        self.path.pop();
+        range
+    }
+    fn v(&mut self, position: usize, e: &VExpr) -> PairRange {
+        self.path.push(position);
+        let range = self.vexpr(e);
+        self.path.pop();
+        range
+    }
+    fn f(&mut self, position: usize, e: &FExpr) {
+        self.path.push(position);
+        self.fexpr(e);
+        self.path.pop();
+    }
+    fn condition(&mut self, e_cond: &IExpr) -> ColorRange {
+        let cond = self.i(0, e_cond);
+        if let Some(holds) = condition(&cond) {
+            self.constant_conditions.push((self.path.clone(), holds));
+        }
+        cond
+    }
+    fn iexpr(&mut self, e: &IExpr) -> ColorRange {
+        use IExpr::*;
+        match e {
+            Lit(n) => [Interval::point(*n); 3],
+            Rgb(rgb) => rgb.map(|n| Interval::point(n as i32)),
+            PixelX => [self.domain.x; 3],
+            PixelY => [self.domain.y; 3],
+            Channel => [-1, 0, 1].map(Interval::point),
+            Time => [self.domain.time; 3],
+            Radius => [radius(self.domain.centered); 3],
+            Angle => [Interval::COMPONENT; 3],
+            Var(n) => match self.vars.len().checked_sub(*n as usize + 1) {
+                Some(i) => self.vars[i],
+                None => [Interval::FULL; 3],
+            },
+            // A channel that's the same everywhere maps to the middle.
+            Scale256(sub_e) | Normalize(_, sub_e) => self.i(0, sub_e)
+                .map(|a| if a.is_point() { Interval::point(127) } else { Interval::COMPONENT }),
+            UnaryI(op, sub_e) => {
+                let range = self.i(0, sub_e);
+                if matches!(op, Unary::Clamp256 | Unary::Mod256) && is_color(&range) {
+                    self.redundant_wrappers.push(self.path.clone());
+                }
+                range.map(|a| unary(*op, a))
+            }
+            BinaryI(op, e_1, e_2) => {
+                let (a, b) = (self.i(0, e_1), self.i(1, e_2));
+                zip_channels(a, b, |a, b| binary(*op, a, b))
+            }
+            BinaryV(op, sub_e) => {
+                let (a, b) = self.v(0, sub_e);
+                zip_channels(a, b, |a, b| binary(*op, a, b))
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                let cond = self.condition(e_cond);
+                let (then, else_) = (self.i(1, e_then), self.i(2, e_else));
+                select(&cond, then, else_)
+            }
+            IfThenElseV(e_cond, e_case) => {
+                let cond = self.i(0, e_cond);
+                let (then, else_) = self.v(1, e_case);
+                select(&cond, then, else_)
+            }
+            FromF(sub_e) => {
+                self.f(0, sub_e);
+                [Interval::FULL; 3]
+            }
+            Noise(_, _, sub_e) => {
+                self.v(0, sub_e);
+                [Interval::COMPONENT; 3]
+            }
+            Let(e_value, e_body) => {
+                let value = self.i(0, e_value);
+                self.vars.push(value);
+                let range = self.i(1, e_body);
+                self.vars.pop();
+                range
+            }
+            // Neighbors and means of values stay within their range.
+            Offset(_, _, sub_e) | Blur(_, sub_e) => self.i(0, sub_e),
+            Sobel(sub_e) => self.i(0, sub_e).map(sobel),
+            Iterate(n, e_body, e_init) => {
+                // An entry's pair stops changing once the body's result
+                // escapes, so the body only ever sees the initial pair or
+                // pairs that haven't escaped.
+                let (x, y) = self.v(1, e_init);
+                let bound = Interval::new(-(ESCAPE_BOUND as i32), ESCAPE_BOUND as i32);
+                self.iterands.push((x.map(|a| a.union(bound)), y.map(|a| a.union(bound))));
+                self.v(0, e_body);
+                self.iterands.pop();
+                [Interval::new(0, *n as i32); 3]
+            }
+        }
+    }
+    fn vexpr(&mut self, e: &VExpr) -> PairRange {
+        use VExpr::*;
+        match e {
+            Pixel => ([self.domain.x; 3], [self.domain.y; 3]),
+            CenteredPixel => ([self.domain.centered.0; 3], [self.domain.centered.1; 3]),
+            Iterand => self.iterands.last().copied().unwrap_or(([Interval::FULL; 3], [Interval::FULL; 3])),
+            Swap(sub_e) => {
+                let (a, b) = self.v(0, sub_e);
+                (b, a)
+            }
+            BinaryI(op_1, op_2, e_1, e_2) => {
+                let (a, b) = (self.i(0, e_1), self.i(1, e_2));
+                (zip_channels(a, b, |a, b| binary(*op_1, a, b)), zip_channels(a, b, |a, b| binary(*op_2, a, b)))
+            }
+            UnaryV(op, sub_e) => {
+                let (a, b) = self.v(0, sub_e);
+                (a.map(|a| unary(*op, a)), b.map(|b| unary(*op, b)))
+            }
+            BinaryV(op, e_1, e_2) => {
+                let ((a_1, a_2), (b_1, b_2)) = (self.v(0, e_1), self.v(1, e_2));
+                (zip_channels(a_1, b_1, |a, b| binary(*op, a, b)), zip_channels(a_2, b_2, |a, b| binary(*op, a, b)))
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                let cond = self.condition(e_cond);
+                let ((then_1, then_2), (else_1, else_2)) = (self.v(1, e_then), self.v(2, e_else));
+                (select(&cond, then_1, else_1), select(&cond, then_2, else_2))
+            }
+            IfThenElseV(e_cond, e_then, e_else) => {
+                let (cond_1, cond_2) = self.v(0, e_cond);
+                let ((then_1, then_2), (else_1, else_2)) = (self.v(1, e_then), self.v(2, e_else));
+                (select(&cond_1, then_1, else_1), select(&cond_2, then_2, else_2))
+            }
+            UnaryC(op, sub_e) => {
+                let a = self.v(0, sub_e);
+                map_complex(a, |z| complex_unary(*op, z))
+            }
+            ComplexMul(e_1, e_2) => {
+                let (a, b) = (self.v(0, e_1), self.v(1, e_2));
+                let products = [0, 1, 2].map(|ch| complex_mul((a.0[ch], a.1[ch]), (b.0[ch], b.1[ch])));
+                (products.map(|(re, _)| re), products.map(|(_, im)| im))
+            }
+        }
+    }
+    /// Floats aren't analyzed, but integer expressions inside them are.
+    fn fexpr(&mut self, e: &FExpr) {
+        use FExpr::*;
+        match e {
+            Lit(_) => {}
+            FromI(sub_e) => {
+                self.i(0, sub_e);
+            }
+            UnaryF(_, sub_e) => self.f(0, sub_e),
+            BinaryF(_, e_1, e_2) => {
+                self.f(0, e_1);
+                self.f(1, e_2);
+            }
+        }
+    }
+}
+
+impl IExpr {
+    /// Find the range of values that each channel of the expression can take
+    /// over `domain` by propagating intervals through it, along with nodes
+    /// that make no difference there. This doesn't evaluate the expression,
+    /// and the ranges it finds may be wider than the values actually taken.
+    pub fn analyze_ranges(&self, domain: &Domain) -> Ranges {
+        let mut analyzer = Analyzer {
+            domain,
+            vars: Vec::new(),
+            iterands: Vec::new(),
+            path: Vec::new(),
+            constant_conditions: Vec::new(),
+            redundant_wrappers: Vec::new(),
+        };
+        let output = analyzer.iexpr(self);
+        Ranges {
+            output,
+            constant_conditions: analyzer.constant_conditions,
+            redundant_wrappers: analyzer.redundant_wrappers,
+        }
+    }
+
+    /// A range that each channel of the expression is guaranteed to be within
+    /// over `domain`.
+    pub fn range(&self, domain: &Domain) -> ColorRange {
+        self.analyze_ranges(domain).output
+    }
+}
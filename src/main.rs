@@ -1,16 +1,38 @@
 use rand::Rng;
 
-use rouille::{Response, router, try_or_400};
+use rouille::{Request, Response, router};
 
+use std::str::FromStr;
 use std::sync::Mutex;
 
 mod utils;
 mod expr;
+mod artwork;
+mod arena;
+mod program;
 mod gen_expr;
 mod display_expr;
+mod parse_expr;
+mod simplify;
+mod canonicalize;
+mod share_expr;
+mod validate;
 mod gen_png;
+mod noise;
+mod viewport;
+mod metrics;
+mod path;
+mod diff_expr;
+mod interval;
+mod dependency;
 
+use artwork::{Artwork, ColorMode, Palette};
+use dependency::Requirements;
 use gen_expr::Parameters;
+use interval::Domain;
+use metrics::Metrics;
+use validate::Limits;
+use viewport::Viewport;
 
 #[derive(Debug)]
 struct ParamPoolEntry {
@@ -28,17 +50,19 @@ impl ParamPoolEntry {
 
 struct ParamPool {
     entries: Vec<ParamPoolEntry>,
+    /// What every generated image must and mustn't depend on.
+    requirements: Requirements,
 }
 
 impl ParamPool {
-    fn new() -> Self {
+    fn new(requirements: Requirements) -> Self {
         Self { entries: vec![
             ParamPoolEntry {
                 upvotes: 1,
                 downvotes: 1,
                 params: Parameters::default(),
             }
-        ] }
+        ], requirements }
     }
     fn get_low_voted_idx<R: Rng>(&self, rng: &mut R) -> usize {
         let mut indices = (0..self.entries.len()).collect::<Vec<_>>();
@@ -54,7 +78,9 @@ impl ParamPool {
         let bound = 1 + rng.gen_range(0, indices.len());
         indices[indices.len() - 1 - rng.gen_range(0, bound)]
     }
-    fn handle_approval(&mut self, param_idx: usize, did_approve: bool) {
+    /// Record a vote on an image generated with the given entry's parameters.
+    /// `metrics` describes the expression that was voted on, if it's known.
+    fn handle_approval(&mut self, param_idx: usize, did_approve: bool, metrics: Option<&Metrics>) {
         let mut rng = rand::thread_rng();
         let entry = &mut self.entries[param_idx];
         if did_approve {
@@ -75,48 +101,220 @@ impl ParamPool {
             }
             self.entries.push(child);
         }
-        println!("Voted.\n{:#?}", self.entries);
+        match metrics {
+            Some(metrics) => println!("Voted on an expression with\n{}", metrics),
+            None => println!("Voted."),
+        }
+        println!("{:#?}", self.entries);
     }
-    fn gen(&self) -> (usize, expr::IExpr) {
+    fn gen(&self) -> (usize, Artwork) {
         let mut rng = rand::thread_rng();
         let idx = self.get_high_voted_idx(&mut rng);
         let params = &self.entries[idx].params;
-        (idx, params.gen_expr(&mut rng, 8, 3))
+        // Judge the expression by how it looks on its page, where it may be
+        // animated.
+        let domain = Domain::new(&Viewport::new(256, 256), ANIMATION_FRAMES);
+        let expr = params.gen_image_expr(&mut rng, 8, 3, &domain, &self.requirements).share_common_subexpressions();
+        let palette = if expr.dependencies().channel { None } else { params.gen_palette(&mut rng) };
+        (idx, Artwork { expr, color_mode: params.gen_color_mode(&mut rng), palette })
     }
 }
 
+/// The number of frames in an animation.
+const ANIMATION_FRAMES: u32 = 32;
+
+/// The largest width or height that an image can be rendered at.
+const MAX_IMAGE_SIZE: u32 = 2048;
+
+/// The most bytes that a single node of an expression takes up when
+/// serialized.
+const MAX_SERIALIZED_NODE_LEN: usize = 16;
+
+/// Decode an artwork serialized in a URL and check that it can be rendered.
+fn decode_artwork(serialized_hex: &str) -> Result<Artwork, String> {
+    let limits = Limits::default();
+    // Anything longer than this has too many nodes to render anyway.
+    if serialized_hex.len() > 2 * MAX_SERIALIZED_NODE_LEN * limits.max_nodes {
+        return Err(format!("expression has more than {} nodes", limits.max_nodes));
+    }
+    let serialized = hex::decode(serialized_hex).map_err(|e| e.to_string())?;
+    let artwork = Artwork::decode(&serialized).map_err(|e| e.to_string())?;
+    artwork.expr.validate(&limits).map_err(|e| e.to_string())?;
+    Ok(artwork)
+}
+
+/// Read a query parameter, using `default` if it's missing.
+fn get_param<T: FromStr>(req: &Request, name: &str, default: T) -> Result<T, String> {
+    match req.get_param(name) {
+        Some(value) => value.parse().map_err(|_| format!("invalid `{}` parameter", name)),
+        None => Ok(default),
+    }
+}
+
+/// Read the viewport to render from the query parameters of a request. By
+/// default, coordinates 0 to 256 are shown at 256 by 256 pixels. `width` and
+/// `height` show the same region at a different size, `zoom` magnifies it and
+/// `x` and `y` choose the coordinates in the middle. Offsets, blurs and edges
+/// are measured in pixels, so those parts of an image don't scale with it.
+fn get_viewport(req: &Request) -> Result<Viewport, String> {
+    let viewport = Viewport::new(256, 256);
+    let width = get_param(req, "width", viewport.width)?;
+    let height = get_param(req, "height", width)?;
+    if !(1..=MAX_IMAGE_SIZE).contains(&width) || !(1..=MAX_IMAGE_SIZE).contains(&height) {
+        return Err(format!("`width` and `height` must be between 1 and {}", MAX_IMAGE_SIZE));
+    }
+    let zoom: f64 = get_param(req, "zoom", 1.0)?;
+    let (x, y) = viewport.middle();
+    let middle = (get_param(req, "x", x)?, get_param(req, "y", y)?);
+    if !(zoom.is_finite() && zoom > 0.0) {
+        return Err("`zoom` must be positive".to_owned());
+    }
+    if !(middle.0.is_finite() && middle.1.is_finite()) {
+        return Err("`x` and `y` must be finite".to_owned());
+    }
+    Ok(viewport.resize(width, height).zoom(zoom).look_at(middle))
+}
+
+// rouille's `router!` expansion strips URL prefixes by hand, which clippy flags.
+#[allow(clippy::manual_strip)]
 fn main() {
-    let state = Mutex::new(ParamPool::new());
+    // Generated images can be limited to ones that depend on particular
+    // inputs with the `REQUIRE` environment variable, such as `x,y,!channel`
+    // for grayscale images that vary along both axes.
+    let requirements = match std::env::var("REQUIRE") {
+        Ok(requirements) => requirements.parse()
+            .expect("`REQUIRE` should list `x`, `y`, `time` and `channel`, each optionally preceded by `!`"),
+        Err(_) => Requirements::default(),
+    };
+    let state = Mutex::new(ParamPool::new(requirements));
+    // The number of threads used to render each image can be set with the
+    // `RENDER_THREADS` environment variable.
+    let render_threads = std::env::var("RENDER_THREADS").ok()
+        .and_then(|threads| threads.parse().ok())
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
     rouille::start_server("localhost:8000", move |req| {
         router!(req,
             (GET) (/) => {
-                let (i, expr) = state.lock().unwrap().gen();
-                let serialized = rmp_serde::to_vec(&expr).unwrap();
+                let (i, artwork) = state.lock().unwrap().gen();
+                let serialized = rmp_serde::to_vec(&artwork).unwrap();
                 Response::redirect_303(format!("desc/{}/{}", i, &hex::encode(serialized)))
             },
             (GET) (/approve/{param_idx: usize}/{did_approve: bool}) => {
+                // The page links here with the formula that was voted on, but
+                // the vote still counts if it's missing or invalid.
+                let metrics = req.get_param("formula")
+                    .and_then(|serialized_hex| decode_artwork(&serialized_hex).ok())
+                    .map(|artwork| artwork.expr.simplify().metrics());
                 let mut state = state.lock().unwrap();
-                state.handle_approval(param_idx, did_approve);
-                let (i, expr) = state.gen();
-                let serialized = rmp_serde::to_vec(&expr).unwrap();
+                state.handle_approval(param_idx, did_approve, metrics.as_ref());
+                let (i, artwork) = state.gen();
+                let serialized = rmp_serde::to_vec(&artwork).unwrap();
                 Response::redirect_303(format!("/desc/{}/{}", i, &hex::encode(serialized)))
             },
             (GET) (/desc/{param_idx: usize}/{serialized_hex: String}) => {
-                let serialized = try_or_400!(hex::decode(&serialized_hex));
-                let expr: expr::IExpr = try_or_400!(rmp_serde::from_slice(&serialized));
+                let artwork = match decode_artwork(&serialized_hex) {
+                    Ok(artwork) => artwork,
+                    Err(e) => return Response::text(e).with_status_code(400),
+                };
+                let expr = artwork.expr.simplify();
+                let (image_kind, frames) = if expr.dependencies().time { ("anim", ANIMATION_FRAMES) } else { ("img", 1) };
                 let html = std::fs::read_to_string("static/desc.html").unwrap();
                 Response::html(html
                     .replace("%PARAM_IDX", &format!("{}", param_idx))
+                    .replace("%IMAGE_KIND", image_kind)
                     .replace("%FORMULA_HEX", &serialized_hex)
+                    .replace("%COLORING", &match &artwork.palette {
+                        Some(palette) => format!("with its first channel mapped through the palette {}", palette),
+                        None => format!("with its channels read as {}", artwork.color_mode),
+                    })
+                    .replace("%METRICS", &format!("{}", expr.metrics()))
+                    .replace("%RANGES", &format!("{}", expr.analyze_ranges(&Domain::new(&Viewport::new(256, 256), frames))))
+                    .replace("%FINGERPRINT", &format!("{:016x}", expr.fingerprint()))
                     .replace("%FORMULA_SEXPR", &format!("{}", expr)))
             },
+            (GET) (/diff/{old_hex: String}/{new_hex: String}) => {
+                let (old, new) = match (decode_artwork(&old_hex), decode_artwork(&new_hex)) {
+                    (Ok(old), Ok(new)) => (old, new),
+                    (Err(e), _) | (_, Err(e)) => return Response::text(e).with_status_code(400),
+                };
+                let diff = old.expr.simplify().diff(&new.expr.simplify());
+                if req.get_param("format").as_deref() == Some("text") {
+                    let edits = diff.edits().iter().map(|edit| format!("{}\n", edit)).collect::<String>();
+                    return Response::text(format!("{}\n\n{}", diff.to_text(), edits));
+                }
+                let edits = diff.edits().iter()
+                    .map(|edit| format!("<li>{}</li>", utils::escape_html(&edit.to_string())))
+                    .collect::<String>();
+                let html = std::fs::read_to_string("static/diff.html").unwrap();
+                Response::html(html
+                    .replace("%OLD_HEX", &old_hex)
+                    .replace("%NEW_HEX", &new_hex)
+                    .replace("%EDITS", &edits)
+                    .replace("%DIFF", &diff.to_html()))
+            },
             (GET) (/img/{serialized_hex: String}) => {
-                let serialized = try_or_400!(hex::decode(&serialized_hex));
-                let expr: expr::IExpr = try_or_400!(rmp_serde::from_slice(&serialized));
+                let artwork = match decode_artwork(&serialized_hex) {
+                    Ok(artwork) => artwork,
+                    Err(e) => return Response::text(e).with_status_code(400),
+                };
+                let viewport = match get_viewport(req) {
+                    Ok(viewport) => viewport,
+                    Err(e) => return Response::text(e).with_status_code(400),
+                };
                 let mut png_data = Vec::new();
-                expr.write_image_data(&mut png_data, 256, 256, 1);
+                if let Err(e) = artwork.write_image_data(&mut png_data, &viewport, render_threads) {
+                    return Response::text(format!("{}", e)).with_status_code(500);
+                }
                 Response::from_data("image/png", png_data)
             },
+            (GET) (/anim/{serialized_hex: String}) => {
+                let artwork = match decode_artwork(&serialized_hex) {
+                    Ok(artwork) => artwork,
+                    Err(e) => return Response::text(e).with_status_code(400),
+                };
+                let viewport = match get_viewport(req) {
+                    Ok(viewport) => viewport,
+                    Err(e) => return Response::text(e).with_status_code(400),
+                };
+                // Every frame is rendered, so keep the whole animation within
+                // the cost of the largest image.
+                let pixels = viewport.width as u64 * viewport.height as u64 * ANIMATION_FRAMES as u64;
+                if pixels > MAX_IMAGE_SIZE as u64 * MAX_IMAGE_SIZE as u64 {
+                    return Response::text(format!(
+                        "animations can have at most {} pixels in each frame",
+                        MAX_IMAGE_SIZE as u64 * MAX_IMAGE_SIZE as u64 / ANIMATION_FRAMES as u64,
+                    )).with_status_code(400);
+                }
+                let mut png_data = Vec::new();
+                if let Err(e) = artwork.write_animation_data(&mut png_data, &viewport, ANIMATION_FRAMES, 60, render_threads) {
+                    return Response::text(format!("{}", e)).with_status_code(500);
+                }
+                Response::from_data("image/png", png_data)
+            },
+            (GET) (/parse) => {
+                let formula = match req.get_param("formula") {
+                    Some(formula) => formula,
+                    None => return Response::text("missing `formula` parameter").with_status_code(400),
+                };
+                let expr: expr::IExpr = match formula.parse() {
+                    Ok(expr) => expr,
+                    Err(e) => return Response::text(format!("{}", e)).with_status_code(400),
+                };
+                if let Err(e) = expr.validate(&Limits::default()) {
+                    return Response::text(format!("{}", e)).with_status_code(400);
+                }
+                let color_mode = match get_param(req, "color_mode", ColorMode::default()) {
+                    Ok(color_mode) => color_mode,
+                    Err(e) => return Response::text(e).with_status_code(400),
+                };
+                let palette = match req.get_param("palette").map(|palette| palette.parse::<Palette>()).transpose() {
+                    Ok(palette) => palette,
+                    Err(()) => return Response::text("invalid `palette` parameter").with_status_code(400),
+                };
+                let serialized = rmp_serde::to_vec(&Artwork { expr, color_mode, palette }).unwrap();
+                Response::redirect_303(format!("/img/{}", &hex::encode(serialized)))
+            },
             _ => {
                 Response::text("404").with_status_code(404)
             }
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::expr::{IExpr, VExpr, FExpr, Unary, Binary};
+
+/// Statistics about the shape of an expression.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub nodes: usize,
+    /// The number of nodes on the longest path from the root to a leaf.
+    pub depth: usize,
+    /// How many times each kind of node appears, named like `IExpr::Lit`.
+    pub variants: BTreeMap<&'static str, usize>,
+    /// How many times each integer operator appears. Operators are counted
+    /// by name, so `DivBy(3)` and `DivBy(5)` are both `DivBy`.
+    pub unary_ops: BTreeMap<&'static str, usize>,
+    pub binary_ops: BTreeMap<&'static str, usize>,
+    /// Whether `PixelX` or a `Pixel` pair appears.
+    pub uses_x: bool,
+    /// Whether `PixelY` or a `Pixel` pair appears.
+    pub uses_y: bool,
+    pub uses_channel: bool,
+}
+
+fn unary_name(op: Unary) -> &'static str {
+    use Unary::*;
+    match op {
+        Square => "Square",
+        Cube => "Cube",
+        Abs => "Abs",
+        Neg => "Neg",
+        DivBy(_) => "DivBy",
+        ModBy(_) => "ModBy",
+        Mod256 => "Mod256",
+        Clamp256 => "Clamp256",
+        Popcount => "Popcount",
+        Sqrt => "Sqrt",
+        Log2 => "Log2",
+        Not => "Not",
+    }
+}
+
+fn binary_name(op: Binary) -> &'static str {
+    use Binary::*;
+    match op {
+        Add => "Add",
+        Sub => "Sub",
+        Mul => "Mul",
+        BitAnd => "BitAnd",
+        BitOr => "BitOr",
+        BitXor => "BitXor",
+        Shl => "Shl",
+        Shr => "Shr",
+        RotL => "RotL",
+        Min => "Min",
+        Max => "Max",
+        Lt => "Lt",
+        Eq => "Eq",
+        Div => "Div",
+        Mod => "Mod",
+    }
+}
+
+impl Metrics {
+    fn unary(&mut self, op: Unary) {
+        *self.unary_ops.entry(unary_name(op)).or_insert(0) += 1;
+    }
+    fn binary(&mut self, op: Binary) {
+        *self.binary_ops.entry(binary_name(op)).or_insert(0) += 1;
+    }
+    /// Count a node at `depth` once its subexpressions have been visited.
+    fn node(&mut self, name: &'static str, depth: usize) {
+        self.nodes += 1;
+        self.depth = std::cmp::max(self.depth, depth);
+        *self.variants.entry(name).or_insert(0) += 1;
+    }
+    fn iexpr(&mut self, e: &IExpr, depth: usize) {
+        use IExpr::*;
+        let name = match e {
+            Lit(_) => "IExpr::Lit",
+            Rgb(_) => "IExpr::Rgb",
+            PixelX => {
+                self.uses_x = true;
+                "IExpr::PixelX"
+            }
+            PixelY => {
+                self.uses_y = true;
+                "IExpr::PixelY"
+            }
+            Channel => {
+                self.uses_channel = true;
+                "IExpr::Channel"
+            }
+            Time => "IExpr::Time",
+            Radius => "IExpr::Radius",
+            Angle => "IExpr::Angle",
+            Var(_) => "IExpr::Var",
+            Scale256(sub_e) => {
+                self.iexpr(sub_e, depth + 1);
+                "IExpr::Scale256"
+            }
+            UnaryI(op, sub_e) => {
+                self.unary(*op);
+                self.iexpr(sub_e, depth + 1);
+                "IExpr::UnaryI"
+            }
+            BinaryI(op, e_1, e_2) => {
+                self.binary(*op);
+                self.iexpr(e_1, depth + 1);
+                self.iexpr(e_2, depth + 1);
+                "IExpr::BinaryI"
+            }
+            BinaryV(op, sub_e) => {
+                self.binary(*op);
+                self.vexpr(sub_e, depth + 1);
+                "IExpr::BinaryV"
+            }
+            IfThenElseI(e_1, e_2, e_3) => {
+                self.iexpr(e_1, depth + 1);
+                self.iexpr(e_2, depth + 1);
+                self.iexpr(e_3, depth + 1);
+                "IExpr::IfThenElseI"
+            }
+            IfThenElseV(e_1, e_2) => {
+                self.iexpr(e_1, depth + 1);
+                self.vexpr(e_2, depth + 1);
+                "IExpr::IfThenElseV"
+            }
+            FromF(sub_e) => {
+                self.fexpr(sub_e, depth + 1);
+                "IExpr::FromF"
+            }
+            Noise(_, _, sub_e) => {
+                self.vexpr(sub_e, depth + 1);
+                "IExpr::Noise"
+            }
+            Let(e_1, e_2) => {
+                self.iexpr(e_1, depth + 1);
+                self.iexpr(e_2, depth + 1);
+                "IExpr::Let"
+            }
+            Normalize(_, sub_e) => {
+                self.iexpr(sub_e, depth + 1);
+                "IExpr::Normalize"
+            }
+            Offset(_, _, sub_e) => {
+                self.iexpr(sub_e, depth + 1);
+                "IExpr::Offset"
+            }
+            Blur(_, sub_e) => {
+                self.iexpr(sub_e, depth + 1);
+                "IExpr::Blur"
+            }
+            Sobel(sub_e) => {
+                self.iexpr(sub_e, depth + 1);
+                "IExpr::Sobel"
+            }
+            Iterate(_, e_1, e_2) => {
+                self.vexpr(e_1, depth + 1);
+                self.vexpr(e_2, depth + 1);
+                "IExpr::Iterate"
+            }
+        };
+        self.node(name, depth);
+    }
+    fn vexpr(&mut self, e: &VExpr, depth: usize) {
+        use VExpr::*;
+        let name = match e {
+            Pixel => {
+                self.uses_x = true;
+                self.uses_y = true;
+                "VExpr::Pixel"
+            }
+            CenteredPixel => "VExpr::CenteredPixel",
+            Iterand => "VExpr::Iterand",
+            Swap(sub_e) => {
+                self.vexpr(sub_e, depth + 1);
+                "VExpr::Swap"
+            }
+            BinaryI(op_1, op_2, e_1, e_2) => {
+                self.binary(*op_1);
+                self.binary(*op_2);
+                self.iexpr(e_1, depth + 1);
+                self.iexpr(e_2, depth + 1);
+                "VExpr::BinaryI"
+            }
+            UnaryV(op, sub_e) => {
+                self.unary(*op);
+                self.vexpr(sub_e, depth + 1);
+                "VExpr::UnaryV"
+            }
+            BinaryV(op, e_1, e_2) => {
+                self.binary(*op);
+                self.vexpr(e_1, depth + 1);
+                self.vexpr(e_2, depth + 1);
+                "VExpr::BinaryV"
+            }
+            IfThenElseI(e_1, e_2, e_3) => {
+                self.iexpr(e_1, depth + 1);
+                self.vexpr(e_2, depth + 1);
+                self.vexpr(e_3, depth + 1);
+                "VExpr::IfThenElseI"
+            }
+            IfThenElseV(e_1, e_2, e_3) => {
+                self.vexpr(e_1, depth + 1);
+                self.vexpr(e_2, depth + 1);
+                self.vexpr(e_3, depth + 1);
+                "VExpr::IfThenElseV"
+            }
+            UnaryC(_, sub_e) => {
+                self.vexpr(sub_e, depth + 1);
+                "VExpr::UnaryC"
+            }
+            ComplexMul(e_1, e_2) => {
+                self.vexpr(e_1, depth + 1);
+                self.vexpr(e_2, depth + 1);
+                "VExpr::ComplexMul"
+            }
+        };
+        self.node(name, depth);
+    }
+    fn fexpr(&mut self, e: &FExpr, depth: usize) {
+        use FExpr::*;
+        let name = match e {
+            Lit(_) => "FExpr::Lit",
+            FromI(sub_e) => {
+                self.iexpr(sub_e, depth + 1);
+                "FExpr::FromI"
+            }
+            UnaryF(_, sub_e) => {
+                self.fexpr(sub_e, depth + 1);
+                "FExpr::UnaryF"
+            }
+            BinaryF(_, e_1, e_2) => {
+                self.fexpr(e_1, depth + 1);
+                self.fexpr(e_2, depth + 1);
+                "FExpr::BinaryF"
+            }
+        };
+        self.node(name, depth);
+    }
+}
+
+/// Write the counts in `counts` as `name count` pairs separated by commas.
+fn write_counts(f: &mut Formatter, counts: &BTreeMap<&'static str, usize>) -> fmt::Result {
+    if counts.is_empty() {
+        return f.write_str("none");
+    }
+    for (i, (name, count)) in counts.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{} {}", name, count)?;
+    }
+    Ok(())
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "nodes: {}, depth: {}", self.nodes, self.depth)?;
+        let inputs = [(self.uses_x, "x"), (self.uses_y, "y"), (self.uses_channel, "channel")]
+            .iter()
+            .filter(|(used, _)| *used)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        writeln!(f, "uses: {}", if inputs.is_empty() { "none".to_owned() } else { inputs.join(", ") })?;
+        f.write_str("variants: ")?;
+        write_counts(f, &self.variants)?;
+        f.write_str("\nunary: ")?;
+        write_counts(f, &self.unary_ops)?;
+        f.write_str("\nbinary: ")?;
+        write_counts(f, &self.binary_ops)
+    }
+}
+
+impl IExpr {
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics::default();
+        metrics.iexpr(self, 1);
+        metrics
+    }
+}
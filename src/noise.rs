@@ -0,0 +1,42 @@
+/// Mix a lattice point and a seed into a pseudorandom 32-bit value.
+fn hash(seed: u32, x: i32, y: i32) -> u32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x9e37_79b1)
+        ^ (y as u32).wrapping_mul(0x85eb_ca77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    h
+}
+
+/// Ease a position between 0 and 256 so that the noise has no visible seams
+/// along the lattice.
+fn fade(t: i32) -> i32 {
+    (t * t * (3 * 256 - 2 * t)) >> 16
+}
+
+fn lerp(a: i32, b: i32, t: i32) -> i32 {
+    a + (((b - a) * t) >> 8)
+}
+
+/// Sample value noise with lattice points `scale` units apart. Only integer
+/// arithmetic is used, so the result is the same on every platform. The result
+/// is between 0 and 255.
+pub fn value_noise(seed: u32, scale: u8, x: i32, y: i32) -> i32 {
+    let scale = scale as i32;
+    let (cell_x, cell_y) = (x.div_euclid(scale), y.div_euclid(scale));
+    let t_x = fade(x.rem_euclid(scale) * 256 / scale);
+    let t_y = fade(y.rem_euclid(scale) * 256 / scale);
+    let corner = |dx, dy| (hash(seed, cell_x.wrapping_add(dx), cell_y.wrapping_add(dy)) & 255) as i32;
+    let top = lerp(corner(0, 0), corner(1, 0), t_x);
+    let bottom = lerp(corner(0, 1), corner(1, 1), t_x);
+    lerp(top, bottom, t_y)
+}
+
+/// Replace each element of `xs` with the noise sampled at it and the
+/// corresponding element of `ys`.
+pub fn eval_slices(seed: u32, scale: u8, xs: &mut [i32], ys: &[i32]) {
+    xs.iter_mut().zip(ys).for_each(|(x, &y)| *x = value_noise(seed, scale, *x, y));
+}
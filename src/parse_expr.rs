@@ -0,0 +1,524 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::expr::{IExpr, VExpr, FExpr, Normalization, Unary, Binary, ComplexUnary, FUnary, FBinary};
+use crate::validate::Limits;
+
+/// An error encountered while parsing an expression, along with the
+/// (1-based) position in the source where it occurred.
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TokenKind {
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+    Atom,
+    End,
+}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl Display for Token<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            TokenKind::End => write!(f, "end of input"),
+            _ => write!(f, "`{}`", self.text),
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = src.char_indices().peekable();
+    let (mut line, mut column) = (1, 1);
+    while let Some((start, ch)) = chars.next() {
+        let kind = match ch {
+            '(' => Some(TokenKind::OpenParen),
+            ')' => Some(TokenKind::CloseParen),
+            '[' => Some(TokenKind::OpenBracket),
+            ']' => Some(TokenKind::CloseBracket),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            tokens.push(Token { kind, text: &src[start..start + 1], line, column });
+            column += 1;
+        } else if ch == '\n' {
+            line += 1;
+            column = 1;
+        } else if ch.is_whitespace() {
+            column += 1;
+        } else {
+            let mut end = start + ch.len_utf8();
+            let token_column = column;
+            column += 1;
+            while let Some(&(i, ch)) = chars.peek() {
+                if ch.is_whitespace() || "()[]".contains(ch) {
+                    break;
+                }
+                end = i + ch.len_utf8();
+                column += 1;
+                chars.next();
+            }
+            tokens.push(Token { kind: TokenKind::Atom, text: &src[start..end], line, column: token_column });
+        }
+    }
+    tokens.push(Token { kind: TokenKind::End, text: "", line, column });
+    tokens
+}
+
+fn parse_normalization(name: &str) -> Option<Normalization> {
+    use Normalization::*;
+    Some(match name {
+        "equalize" => Equalize,
+        "rank" => Rank,
+        _ => Percentile(name.strip_prefix("percentile-")?.parse().ok()?),
+    })
+}
+
+fn parse_unary(name: &str) -> Option<Unary> {
+    use Unary::*;
+    Some(match name {
+        "square" => Square,
+        "cube" => Cube,
+        "abs" => Abs,
+        "neg" => Neg,
+        "mod-256" => Mod256,
+        "clamp" => Clamp256,
+        "popcount" => Popcount,
+        "isqrt" => Sqrt,
+        "ilog2" => Log2,
+        "not" => Not,
+        _ => if let Some(n) = name.strip_prefix('/') {
+            DivBy(n.parse().ok()?)
+        } else if let Some(n) = name.strip_prefix('%') {
+            ModBy(n.parse().ok()?)
+        } else {
+            return None
+        }
+    })
+}
+
+fn parse_binary(name: &str) -> Option<Binary> {
+    use Binary::*;
+    Some(match name {
+        "+" => Add,
+        "-" => Sub,
+        "*" => Mul,
+        "&" => BitAnd,
+        "|" => BitOr,
+        "^" => BitXor,
+        "<<" => Shl,
+        ">>" => Shr,
+        "rotl" => RotL,
+        "min" => Min,
+        "max" => Max,
+        "<" => Lt,
+        "=" => Eq,
+        "/" => Div,
+        "%" => Mod,
+        _ => return None,
+    })
+}
+
+fn parse_complex_unary(name: &str) -> Option<ComplexUnary> {
+    use ComplexUnary::*;
+    Some(match name {
+        "conj" => Conj,
+        "csquare" => Square,
+        "cnorm" => SquaredMagnitude,
+        _ => return None,
+    })
+}
+
+fn parse_funary(name: &str) -> Option<FUnary> {
+    use FUnary::*;
+    Some(match name {
+        "sin" => Sin,
+        "cos" => Cos,
+        "sqrt" => Sqrt,
+        "exp" => Exp,
+        _ => return None,
+    })
+}
+
+fn parse_fbinary(name: &str) -> Option<FBinary> {
+    use FBinary::*;
+    Some(match name {
+        "+." => Add,
+        "-." => Sub,
+        "*." => Mul,
+        "/." => Div,
+        "atan2" => Atan2,
+        _ => return None,
+    })
+}
+
+fn parse_rgb(text: &str) -> Option<[u8; 3]> {
+    let mut parts = text.split('/');
+    let r = parts.next()?.parse().ok()?;
+    let g = parts.next()?.parse().ok()?;
+    let b = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some([r, g, b])
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    /// The names of the variables in scope, innermost last.
+    scope: Vec<&'a str>,
+    /// The number of operators around the current position. This is kept
+    /// under the depth limit for rendering so that deeply nested input
+    /// can't overflow the stack.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        Parser { tokens: tokenize(src), pos: 0, scope: Vec::new(), depth: 0 }
+    }
+    fn peek(&self) -> Token<'a> {
+        self.tokens[self.pos]
+    }
+    fn next(&mut self) -> Token<'a> {
+        let token = self.peek();
+        if token.kind != TokenKind::End {
+            self.pos += 1;
+        }
+        token
+    }
+    fn error<T>(token: Token, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError { line: token.line, column: token.column, message: message.into() })
+    }
+    fn expect(&mut self, kind: TokenKind, what: &str) -> Result<Token<'a>, ParseError> {
+        let token = self.next();
+        if token.kind == kind {
+            Ok(token)
+        } else {
+            Self::error(token, format!("expected {}, found {}", what, token))
+        }
+    }
+    fn close_paren(&mut self) -> Result<(), ParseError> {
+        self.expect(TokenKind::CloseParen, "`)`").map(drop)
+    }
+    fn close_bracket(&mut self) -> Result<(), ParseError> {
+        self.expect(TokenKind::CloseBracket, "`]`").map(drop)
+    }
+    /// Go one operator deeper, given the token that opens it.
+    fn descend(&mut self, token: Token) -> Result<(), ParseError> {
+        self.depth += 1;
+        let max_depth = Limits::default().max_depth;
+        if self.depth > max_depth {
+            Self::error(token, format!("expression is nested more than {} levels deep", max_depth))
+        } else {
+            Ok(())
+        }
+    }
+    fn operator(&mut self) -> Result<Token<'a>, ParseError> {
+        self.expect(TokenKind::Atom, "an operator")
+    }
+    fn binary(&mut self) -> Result<Binary, ParseError> {
+        let token = self.operator()?;
+        match parse_binary(token.text) {
+            Some(op) => Ok(op),
+            None => Self::error(token, format!("expected a binary operator, found {}", token)),
+        }
+    }
+    fn number<T: FromStr>(&mut self, what: &str) -> Result<T, ParseError> {
+        let token = self.expect(TokenKind::Atom, what)?;
+        match token.text.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => Self::error(token, format!("expected {}, found {}", what, token)),
+        }
+    }
+    /// Whether the next token begins a pair expression rather than an
+    /// integer expression.
+    fn at_vexpr(&self) -> bool {
+        let token = self.peek();
+        match token.kind {
+            TokenKind::OpenBracket => true,
+            TokenKind::Atom => token.text == "xy" || token.text == "cxy"
+                || (token.text == "z" && !self.scope.contains(&"z")),
+            _ => false,
+        }
+    }
+    fn iexpr(&mut self) -> Result<IExpr, ParseError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Atom if self.scope.contains(&token.text) => {
+                let index = self.scope.iter().rev().position(|&name| name == token.text).unwrap();
+                Ok(IExpr::Var(index as u32))
+            }
+            TokenKind::Atom => match token.text {
+                "x" => Ok(IExpr::PixelX),
+                "y" => Ok(IExpr::PixelY),
+                "c" => Ok(IExpr::Channel),
+                "t" => Ok(IExpr::Time),
+                "r" => Ok(IExpr::Radius),
+                "a" => Ok(IExpr::Angle),
+                text => if let Ok(n) = text.parse() {
+                    Ok(IExpr::Lit(n))
+                } else if let Some(rgb) = parse_rgb(text) {
+                    Ok(IExpr::Rgb(rgb))
+                } else if let Some(n) = text.strip_prefix('?').and_then(|n| n.parse().ok()) {
+                    Ok(IExpr::Var(n))
+                } else {
+                    Self::error(token, format!("expected an integer expression, found {}", token))
+                }
+            }
+            TokenKind::OpenParen => {
+                self.descend(token)?;
+                let head = self.operator()?;
+                let expr = if head.text == "scale-256" {
+                    IExpr::Scale256(Box::new(self.iexpr()?))
+                } else if head.text == "noise" {
+                    let seed = self.number("a seed")?;
+                    let scale = self.number("a scale from 0 to 255")?;
+                    IExpr::Noise(seed, scale, Box::new(self.vexpr()?))
+                } else if head.text == "offset" {
+                    let dx = self.number("an offset from -128 to 127")?;
+                    let dy = self.number("an offset from -128 to 127")?;
+                    IExpr::Offset(dx, dy, Box::new(self.iexpr()?))
+                } else if head.text == "blur" {
+                    let radius = self.number("a radius from 0 to 255")?;
+                    IExpr::Blur(radius, Box::new(self.iexpr()?))
+                } else if head.text == "sobel" {
+                    IExpr::Sobel(Box::new(self.iexpr()?))
+                } else if head.text == "iterate" {
+                    let n = self.number("an iteration count from 0 to 255")?;
+                    let e_body = Box::new(self.vexpr()?);
+                    IExpr::Iterate(n, e_body, Box::new(self.vexpr()?))
+                } else if let Some(kind) = parse_normalization(head.text) {
+                    IExpr::Normalize(kind, Box::new(self.iexpr()?))
+                } else if head.text == "let" {
+                    self.let_expr()?
+                } else if head.text == "int" {
+                    IExpr::FromF(Box::new(self.fexpr()?))
+                } else if head.text == "?" {
+                    let e_cond = Box::new(self.iexpr()?);
+                    if self.at_vexpr() {
+                        IExpr::IfThenElseV(e_cond, Box::new(self.vexpr()?))
+                    } else {
+                        let e_then = Box::new(self.iexpr()?);
+                        IExpr::IfThenElseI(e_cond, e_then, Box::new(self.iexpr()?))
+                    }
+                } else if let Some(op) = parse_unary(head.text) {
+                    IExpr::UnaryI(op, Box::new(self.iexpr()?))
+                } else if let Some(op) = parse_binary(head.text) {
+                    if self.at_vexpr() {
+                        IExpr::BinaryV(op, Box::new(self.vexpr()?))
+                    } else {
+                        let e_1 = Box::new(self.iexpr()?);
+                        IExpr::BinaryI(op, e_1, Box::new(self.iexpr()?))
+                    }
+                } else {
+                    return Self::error(head, format!("unknown operator {}", head));
+                };
+                self.close_paren()?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            _ => Self::error(token, format!("expected an integer expression, found {}", token)),
+        }
+    }
+    fn vexpr(&mut self) -> Result<VExpr, ParseError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Atom if token.text == "xy" => Ok(VExpr::Pixel),
+            TokenKind::Atom if token.text == "cxy" => Ok(VExpr::CenteredPixel),
+            TokenKind::Atom if token.text == "z" => Ok(VExpr::Iterand),
+            TokenKind::OpenBracket => {
+                self.descend(token)?;
+                let expr = if self.peek().kind == TokenKind::OpenBracket {
+                    self.next();
+                    let op_1 = self.binary()?;
+                    let op_2 = self.binary()?;
+                    self.close_bracket()?;
+                    let e_1 = Box::new(self.iexpr()?);
+                    VExpr::BinaryI(op_1, op_2, e_1, Box::new(self.iexpr()?))
+                } else {
+                    let head = self.operator()?;
+                    if head.text == "swap" {
+                        VExpr::Swap(Box::new(self.vexpr()?))
+                    } else if head.text == "?" {
+                        let e_cond = Box::new(self.iexpr()?);
+                        let e_then = Box::new(self.vexpr()?);
+                        VExpr::IfThenElseI(e_cond, e_then, Box::new(self.vexpr()?))
+                    } else if head.text == "?v" {
+                        let e_cond = Box::new(self.vexpr()?);
+                        let e_then = Box::new(self.vexpr()?);
+                        VExpr::IfThenElseV(e_cond, e_then, Box::new(self.vexpr()?))
+                    } else if head.text == "c*" {
+                        let e_1 = Box::new(self.vexpr()?);
+                        VExpr::ComplexMul(e_1, Box::new(self.vexpr()?))
+                    } else if let Some(op) = parse_complex_unary(head.text) {
+                        VExpr::UnaryC(op, Box::new(self.vexpr()?))
+                    } else if let Some(op) = parse_unary(head.text) {
+                        VExpr::UnaryV(op, Box::new(self.vexpr()?))
+                    } else if let Some(op) = parse_binary(head.text) {
+                        let e_1 = Box::new(self.vexpr()?);
+                        VExpr::BinaryV(op, e_1, Box::new(self.vexpr()?))
+                    } else {
+                        return Self::error(head, format!("unknown operator {}", head));
+                    }
+                };
+                self.close_bracket()?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            _ => Self::error(token, format!("expected a pair expression, found {}", token)),
+        }
+    }
+    /// Parse the bindings and body of a `let`, after the `let` itself. Each
+    /// binding is in scope for the bindings after it as well as the body.
+    fn let_expr(&mut self) -> Result<IExpr, ParseError> {
+        self.expect(TokenKind::OpenParen, "`(`")?;
+        let mut values = Vec::new();
+        while self.peek().kind == TokenKind::OpenParen {
+            let token = self.next();
+            // Each binding after the first is nested in another `Let`.
+            if !values.is_empty() {
+                self.descend(token)?;
+            }
+            let name = self.expect(TokenKind::Atom, "a variable name")?;
+            values.push(self.iexpr()?);
+            self.close_paren()?;
+            self.scope.push(name.text);
+        }
+        self.close_paren()?;
+        let body = self.iexpr()?;
+        self.scope.truncate(self.scope.len() - values.len());
+        self.depth -= values.len().saturating_sub(1);
+        Ok(values.into_iter().rev().fold(body, |body, value| IExpr::Let(Box::new(value), Box::new(body))))
+    }
+    fn fexpr(&mut self) -> Result<FExpr, ParseError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Atom => match token.text.parse() {
+                Ok(n) => Ok(FExpr::Lit(n)),
+                Err(_) => Self::error(token, format!("expected a float expression, found {}", token)),
+            }
+            TokenKind::OpenParen => {
+                self.descend(token)?;
+                let head = self.operator()?;
+                let expr = if head.text == "float" {
+                    FExpr::FromI(Box::new(self.iexpr()?))
+                } else if let Some(op) = parse_funary(head.text) {
+                    FExpr::UnaryF(op, Box::new(self.fexpr()?))
+                } else if let Some(op) = parse_fbinary(head.text) {
+                    let e_1 = Box::new(self.fexpr()?);
+                    FExpr::BinaryF(op, e_1, Box::new(self.fexpr()?))
+                } else {
+                    return Self::error(head, format!("unknown operator {}", head));
+                };
+                self.close_paren()?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            _ => Self::error(token, format!("expected a float expression, found {}", token)),
+        }
+    }
+    fn end(&mut self) -> Result<(), ParseError> {
+        self.expect(TokenKind::End, "end of input").map(drop)
+    }
+}
+
+/// Parse an integer expression from the syntax produced by its `Display`
+/// implementation.
+pub fn parse_iexpr(src: &str) -> Result<IExpr, ParseError> {
+    let mut parser = Parser::new(src);
+    let expr = parser.iexpr()?;
+    parser.end()?;
+    Ok(expr)
+}
+
+/// Parse a pair expression from the syntax produced by its `Display`
+/// implementation.
+pub fn parse_vexpr(src: &str) -> Result<VExpr, ParseError> {
+    let mut parser = Parser::new(src);
+    let expr = parser.vexpr()?;
+    parser.end()?;
+    Ok(expr)
+}
+
+/// Parse a float expression from the syntax produced by its `Display`
+/// implementation.
+pub fn parse_fexpr(src: &str) -> Result<FExpr, ParseError> {
+    let mut parser = Parser::new(src);
+    let expr = parser.fexpr()?;
+    parser.end()?;
+    Ok(expr)
+}
+
+impl FromStr for IExpr {
+    type Err = ParseError;
+    fn from_str(src: &str) -> Result<Self, ParseError> {
+        parse_iexpr(src)
+    }
+}
+
+impl FromStr for VExpr {
+    type Err = ParseError;
+    fn from_str(src: &str) -> Result<Self, ParseError> {
+        parse_vexpr(src)
+    }
+}
+
+impl FromStr for FExpr {
+    type Err = ParseError;
+    fn from_str(src: &str) -> Result<Self, ParseError> {
+        parse_fexpr(src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> String {
+        format!("{}x{}", "(neg ".repeat(depth), ")".repeat(depth))
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let max_depth = Limits::default().max_depth;
+        assert!(parse_iexpr(&nested(max_depth)).is_ok());
+        assert!(parse_iexpr(&nested(max_depth + 1)).is_err());
+        assert!(parse_iexpr(&nested(4000)).is_err());
+        assert!(parse_vexpr(&format!("{}xy{}", "[neg ".repeat(4000), "]".repeat(4000))).is_err());
+        assert!(parse_fexpr(&format!("{}1{}", "(sin ".repeat(4000), ")".repeat(4000))).is_err());
+    }
+
+    #[test]
+    fn long_binding_lists_are_an_error() {
+        let bindings = |n| format!("(let ({}) x)", "(a 1) ".repeat(n));
+        let max_depth = Limits::default().max_depth;
+        assert!(parse_iexpr(&bindings(max_depth)).is_ok());
+        assert!(parse_iexpr(&bindings(max_depth + 1)).is_err());
+        assert!(parse_iexpr(&bindings(200_000)).is_err());
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt::{self, Formatter};
+
+/// A step from a node to one of its subexpressions, counting from 0 in the
+/// order that they're displayed.
+pub type Path = Vec<usize>;
+
+/// Write a path as `path 0.1`, or `the root` if it's empty.
+pub fn write_path(f: &mut Formatter, path: &Path) -> fmt::Result {
+    if path.is_empty() {
+        return f.write_str("the root");
+    }
+    f.write_str("path ")?;
+    for (i, step) in path.iter().enumerate() {
+        if i > 0 {
+            f.write_str(".")?;
+        }
+        write!(f, "{}", step)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,895 @@
+use crate::arena::{ExprArena, IExprId, VExprId, FExprId, INode, VNode, FNode};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use crate::expr::{IExpr, Color, Normalization, Unary, Binary, ComplexUnary, FUnary, FBinary, ESCAPE_BOUND, complex_mul_slices};
+use crate::noise;
+use crate::utils;
+
+/// A single step of a `Program`. Each instruction pops its operands off of the
+/// stack and pushes its results; pairs take up two stack slots, and floats
+/// are stored as their bit patterns.
+#[derive(Clone, Copy)]
+pub enum Instr {
+    Lit(i32),
+    Rgb([u8; 3]),
+    PixelX,
+    PixelY,
+    Channel,
+    Time,
+    /// The coordinates of the pixel relative to the center of the image.
+    CenteredX,
+    CenteredY,
+    Radius,
+    Angle,
+    /// Sample noise at the top pair.
+    Noise(u32, u8),
+    /// Move the top item into a new variable.
+    Bind,
+    /// Discard the innermost variable.
+    Unbind,
+    /// Push the value of a variable, counting outwards from the innermost.
+    Load(u32),
+    /// Repeatedly apply the loop body made up of the given number of
+    /// instructions after this one to the top pair, replacing it with the
+    /// number of iterations before it escaped.
+    Iterate(u8, u32),
+    /// Push the pair that the innermost loop body is being applied to.
+    Iterand,
+    Scale256,
+    Normalize(Normalization),
+    /// Read the top item at a neighboring pixel.
+    Offset(i8, i8),
+    /// Average the top item over the surrounding pixels.
+    Blur(u8),
+    /// Find the gradient magnitude of the top item.
+    Sobel,
+    /// Apply an operator to the top item.
+    Unary(Unary),
+    /// Apply an operator to the top two items.
+    Binary(Binary),
+    /// Swap the top two items.
+    Swap,
+    /// Apply an operator to both elements of the top pair.
+    UnaryPair(Unary),
+    /// Apply an operator lane by lane to the top two pairs.
+    BinaryPair(Binary),
+    /// Apply two operators to the top two items, giving a pair.
+    BinaryToPair(Binary, Binary),
+    /// Apply a complex operator to the top pair.
+    UnaryComplex(ComplexUnary),
+    /// Multiply the top two pairs as complex numbers.
+    ComplexMul,
+    /// Choose between the top two items based on the condition below them.
+    Select,
+    /// Choose between the top two pairs based on the condition below them.
+    SelectPair,
+    /// Choose between the top two pairs lane by lane based on the condition
+    /// pair below them.
+    SelectPairLanes,
+    LitF(f32),
+    /// Apply a float operator to the top item.
+    UnaryF(FUnary),
+    /// Apply a float operator to the top two items.
+    BinaryF(FBinary),
+    IntToFloat,
+    FloatToInt,
+}
+
+impl Instr {
+    /// Whether the instruction depends on the values of every batch, and so
+    /// can't be evaluated by a single batch on its own.
+    fn is_barrier(self) -> bool {
+        matches!(self, Instr::Scale256 | Instr::Normalize(_) | Instr::Offset(..) | Instr::Blur(_) | Instr::Sobel)
+    }
+}
+
+/// How the inputs of a program are arranged into an image. Neighborhood
+/// instructions only see this grid, not the coordinates it was sampled at, so
+/// they measure distances in pixels.
+#[derive(Clone, Copy)]
+pub struct Layout {
+    /// The point that polar coordinates are measured from.
+    pub center: (i32, i32),
+    /// The size of each frame in pixels. The inputs, in order, cover each
+    /// frame row by row, which is how neighboring pixels are found.
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Layout {
+    /// Find the value of the pixel `dx` columns right of and `dy` rows down
+    /// from the pixel at `index`, where `values` holds the value of every
+    /// pixel. Pixels past the edge of the frame take the value of the nearest
+    /// pixel on the edge.
+    fn neighbor(&self, values: &[i32], index: usize, dx: i32, dy: i32) -> i32 {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let frame_start = index - index % (width * height);
+        let column = utils::clamp(0, width as i32 - 1, (index % width) as i32 + dx) as usize;
+        let row = utils::clamp(0, height as i32 - 1, (index / width % height) as i32 + dy) as usize;
+        values[frame_start + row * width + column]
+    }
+    /// Evaluate a neighborhood instruction for the pixel at `index`.
+    fn sample(&self, instr: Instr, values: &[i32], index: usize) -> i32 {
+        let at = |dx, dy| self.neighbor(values, index, dx, dy) as i64;
+        match instr {
+            Instr::Offset(dx, dy) => at(dx as i32, dy as i32) as i32,
+            Instr::Blur(radius) => {
+                let radius = radius as i32;
+                let sum = (-radius..=radius)
+                    .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+                    .map(|(dx, dy)| at(dx, dy))
+                    .sum::<i64>();
+                let count = (2 * radius as i64 + 1).pow(2);
+                sum.div_euclid(count) as i32
+            }
+            Instr::Sobel => {
+                let gx = at(1, -1) + 2 * at(1, 0) + at(1, 1) - at(-1, -1) - 2 * at(-1, 0) - at(-1, 1);
+                let gy = at(-1, 1) + 2 * at(0, 1) + at(1, 1) - at(-1, -1) - 2 * at(0, -1) - at(1, -1);
+                let magnitude = ((gx as i128).pow(2) as u128 + (gy as i128).pow(2) as u128).isqrt();
+                std::cmp::min(magnitude, i32::MAX as u128) as i32
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A problem that stopped a program from being evaluated.
+#[derive(Debug)]
+pub enum EvalError {
+    /// A value being normalized was outside of the range of values that it
+    /// was normalized over.
+    OutOfRange { value: i32, minimum: i32, maximum: i32 },
+    /// The result of the program isn't a valid color component.
+    InvalidColor(i32),
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            EvalError::OutOfRange { value, minimum, maximum } =>
+                write!(f, "{} is outside of the range {} to {} that it was normalized over", value, minimum, maximum),
+            EvalError::InvalidColor(n) =>
+                write!(f, "expression produced {}, which isn't between 0 and 255", n),
+        }
+    }
+}
+
+impl Error for EvalError {}
+
+/// An expression lowered to a flat postfix sequence of instructions for a
+/// stack machine.
+#[derive(Clone)]
+pub struct Program {
+    instrs: Vec<Instr>,
+}
+
+impl ExprArena {
+    fn compile_i(&self, id: IExprId, out: &mut Vec<Instr>) {
+        use INode::*;
+        match self.inode(id) {
+            Lit(n) => out.push(Instr::Lit(n)),
+            Rgb(rgb) => out.push(Instr::Rgb(rgb)),
+            PixelX => out.push(Instr::PixelX),
+            PixelY => out.push(Instr::PixelY),
+            Channel => out.push(Instr::Channel),
+            Time => out.push(Instr::Time),
+            Radius => out.push(Instr::Radius),
+            Angle => out.push(Instr::Angle),
+            Noise(seed, scale, sub_e) => {
+                self.compile_v(sub_e, out);
+                out.push(Instr::Noise(seed, scale));
+            }
+            Normalize(kind, sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::Normalize(kind));
+            }
+            Offset(dx, dy, sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::Offset(dx, dy));
+            }
+            Blur(radius, sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::Blur(radius));
+            }
+            Sobel(sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::Sobel);
+            }
+            Let(e_value, e_body) => {
+                self.compile_i(e_value, out);
+                out.push(Instr::Bind);
+                self.compile_i(e_body, out);
+                out.push(Instr::Unbind);
+            }
+            Var(n) => out.push(Instr::Load(n)),
+            Scale256(sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::Scale256);
+            }
+            UnaryI(op, sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::Unary(op));
+            }
+            BinaryI(op, e_1, e_2) => {
+                self.compile_i(e_1, out);
+                self.compile_i(e_2, out);
+                out.push(Instr::Binary(op));
+            }
+            BinaryV(op, sub_e) => {
+                self.compile_v(sub_e, out);
+                out.push(Instr::Binary(op));
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                self.compile_i(e_cond, out);
+                self.compile_i(e_then, out);
+                self.compile_i(e_else, out);
+                out.push(Instr::Select);
+            }
+            IfThenElseV(e_cond, e_case) => {
+                self.compile_i(e_cond, out);
+                self.compile_v(e_case, out);
+                out.push(Instr::Select);
+            }
+            FromF(sub_e) => {
+                self.compile_f(sub_e, out);
+                out.push(Instr::FloatToInt);
+            }
+            Iterate(n, e_body, e_init) => {
+                self.compile_v(e_init, out);
+                let start = out.len();
+                out.push(Instr::Iterate(n, 0));
+                self.compile_v(e_body, out);
+                out[start] = Instr::Iterate(n, (out.len() - start - 1) as u32);
+            }
+        }
+    }
+
+    fn compile_v(&self, id: VExprId, out: &mut Vec<Instr>) {
+        use VNode::*;
+        match self.vnode(id) {
+            Pixel => {
+                out.push(Instr::PixelX);
+                out.push(Instr::PixelY);
+            }
+            CenteredPixel => {
+                out.push(Instr::CenteredX);
+                out.push(Instr::CenteredY);
+            }
+            Iterand => out.push(Instr::Iterand),
+            Swap(sub_e) => {
+                self.compile_v(sub_e, out);
+                out.push(Instr::Swap);
+            }
+            BinaryI(op_1, op_2, e_1, e_2) => {
+                self.compile_i(e_1, out);
+                self.compile_i(e_2, out);
+                out.push(Instr::BinaryToPair(op_1, op_2));
+            }
+            UnaryV(op, sub_e) => {
+                self.compile_v(sub_e, out);
+                out.push(Instr::UnaryPair(op));
+            }
+            BinaryV(op, e_1, e_2) => {
+                self.compile_v(e_1, out);
+                self.compile_v(e_2, out);
+                out.push(Instr::BinaryPair(op));
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                self.compile_i(e_cond, out);
+                self.compile_v(e_then, out);
+                self.compile_v(e_else, out);
+                out.push(Instr::SelectPair);
+            }
+            IfThenElseV(e_cond, e_then, e_else) => {
+                self.compile_v(e_cond, out);
+                self.compile_v(e_then, out);
+                self.compile_v(e_else, out);
+                out.push(Instr::SelectPairLanes);
+            }
+            UnaryC(op, sub_e) => {
+                self.compile_v(sub_e, out);
+                out.push(Instr::UnaryComplex(op));
+            }
+            ComplexMul(e_1, e_2) => {
+                self.compile_v(e_1, out);
+                self.compile_v(e_2, out);
+                out.push(Instr::ComplexMul);
+            }
+        }
+    }
+
+    fn compile_f(&self, id: FExprId, out: &mut Vec<Instr>) {
+        use FNode::*;
+        match self.fnode(id) {
+            Lit(n) => out.push(Instr::LitF(n)),
+            FromI(sub_e) => {
+                self.compile_i(sub_e, out);
+                out.push(Instr::IntToFloat);
+            }
+            UnaryF(op, sub_e) => {
+                self.compile_f(sub_e, out);
+                out.push(Instr::UnaryF(op));
+            }
+            BinaryF(op, e_1, e_2) => {
+                self.compile_f(e_1, out);
+                self.compile_f(e_2, out);
+                out.push(Instr::BinaryF(op));
+            }
+        }
+    }
+
+    pub fn compile(&self, root: IExprId) -> Program {
+        let mut instrs = Vec::new();
+        self.compile_i(root, &mut instrs);
+        Program { instrs }
+    }
+}
+
+impl IExpr {
+    pub fn compile(&self) -> Program {
+        let (arena, root) = ExprArena::from_iexpr(self);
+        arena.compile(root)
+    }
+}
+
+/// One stack slot, holding a value for every entry of the batch.
+#[derive(Clone)]
+enum Column {
+    /// Every channel has the same value.
+    Same(Vec<i32>),
+    Rgb([Vec<i32>; 3]),
+}
+
+impl Column {
+    fn channel(&self, ch: usize) -> &[i32] {
+        match self {
+            Column::Same(ns) => ns,
+            Column::Rgb(channels) => &channels[ch],
+        }
+    }
+    fn channels_mut(&mut self) -> &mut [Vec<i32>] {
+        match self {
+            Column::Same(ns) => std::slice::from_mut(ns),
+            Column::Rgb(channels) => channels,
+        }
+    }
+    fn into_rgb(self) -> [Vec<i32>; 3] {
+        match self {
+            Column::Same(ns) => [ns.clone(), ns.clone(), ns],
+            Column::Rgb(channels) => channels,
+        }
+    }
+    fn color(&self, idx: usize) -> Color {
+        [self.channel(0)[idx], self.channel(1)[idx], self.channel(2)[idx]]
+    }
+    fn map(mut self, f: impl Fn(&mut [i32])) -> Self {
+        for ns in self.channels_mut() {
+            f(ns);
+        }
+        self
+    }
+    fn zip(self, other: &Self, f: impl Fn(&mut [i32], &[i32])) -> Self {
+        match (self, other) {
+            (Column::Same(mut a), Column::Same(b)) => {
+                f(&mut a, b);
+                Column::Same(a)
+            }
+            (this, other) => {
+                let mut channels = this.into_rgb();
+                for (ch, ns) in channels.iter_mut().enumerate() {
+                    f(ns, other.channel(ch));
+                }
+                Column::Rgb(channels)
+            }
+        }
+    }
+    fn eval_unary(self, op: Unary) -> Self {
+        self.map(|ns| op.eval_slice(ns))
+    }
+    fn eval_binary(self, other: &Self, op: Binary) -> Self {
+        self.zip(other, |a, b| op.eval_slices(a, b))
+    }
+    fn eval_funary(self, op: FUnary) -> Self {
+        self.map(|ns| op.eval_slice(ns))
+    }
+    fn eval_fbinary(self, other: &Self, op: FBinary) -> Self {
+        self.zip(other, |a, b| op.eval_slices(a, b))
+    }
+    fn int_to_float(self) -> Self {
+        self.map(|ns| ns.iter_mut().for_each(|n| *n = (*n as f32).to_bits() as i32))
+    }
+    fn float_to_int(self) -> Self {
+        self.map(|ns| ns.iter_mut().for_each(|n| *n = f32::from_bits(*n as u32) as i32))
+    }
+    /// Replace the elements of a pair of columns using `f`, which is given the
+    /// corresponding channels of both columns of the pair.
+    fn map_pair(a_1: Self, a_2: Self, f: impl Fn(&mut [i32], &mut [i32])) -> (Self, Self) {
+        match (a_1, a_2) {
+            (Column::Same(mut a_1), Column::Same(mut a_2)) => {
+                f(&mut a_1, &mut a_2);
+                (Column::Same(a_1), Column::Same(a_2))
+            }
+            (a_1, a_2) => {
+                let (mut a_1, mut a_2) = (a_1.into_rgb(), a_2.into_rgb());
+                for (ns_1, ns_2) in a_1.iter_mut().zip(a_2.iter_mut()) {
+                    f(ns_1, ns_2);
+                }
+                (Column::Rgb(a_1), Column::Rgb(a_2))
+            }
+        }
+    }
+    /// Like `map_pair`, but `f` is also given the corresponding channels of
+    /// another pair.
+    fn zip_pair(a_1: Self, a_2: Self, b_1: &Self, b_2: &Self, f: impl Fn(&mut [i32], &mut [i32], &[i32], &[i32])) -> (Self, Self) {
+        match (a_1, a_2, b_1, b_2) {
+            (Column::Same(mut a_1), Column::Same(mut a_2), Column::Same(b_1), Column::Same(b_2)) => {
+                f(&mut a_1, &mut a_2, b_1, b_2);
+                (Column::Same(a_1), Column::Same(a_2))
+            }
+            (a_1, a_2, b_1, b_2) => {
+                let (mut a_1, mut a_2) = (a_1.into_rgb(), a_2.into_rgb());
+                for (ch, (ns_1, ns_2)) in a_1.iter_mut().zip(a_2.iter_mut()).enumerate() {
+                    f(ns_1, ns_2, b_1.channel(ch), b_2.channel(ch));
+                }
+                (Column::Rgb(a_1), Column::Rgb(a_2))
+            }
+        }
+    }
+    fn select(cond: &Self, then: &Self, else_: &Self) -> Self {
+        let select_channel = |ch| cond.channel(ch).iter()
+            .zip(then.channel(ch))
+            .zip(else_.channel(ch))
+            .map(|((&c, &t), &e)| if c > 0 { t } else { e })
+            .collect();
+        match (cond, then, else_) {
+            (Column::Same(_), Column::Same(_), Column::Same(_)) => Column::Same(select_channel(0)),
+            _ => Column::Rgb([select_channel(0), select_channel(1), select_channel(2)]),
+        }
+    }
+}
+
+/// The smallest and largest value in each channel of a stack slot.
+#[derive(Clone, Copy)]
+struct ChannelRange {
+    minimums: [i32; 3],
+    maximums: [i32; 3],
+}
+
+impl ChannelRange {
+    const EMPTY: Self = Self { minimums: [i32::MAX; 3], maximums: [i32::MIN; 3] };
+
+    fn union(self, other: Self) -> Self {
+        let mut range = self;
+        for ch in 0..3 {
+            range.minimums[ch] = std::cmp::min(range.minimums[ch], other.minimums[ch]);
+            range.maximums[ch] = std::cmp::max(range.maximums[ch], other.maximums[ch]);
+        }
+        range
+    }
+}
+
+/// What a barrier needs to know about the values of the top stack slot across
+/// every batch.
+enum Summary {
+    Range(ChannelRange),
+    /// Every value in each channel, in the order of the inputs. Once the
+    /// summaries of every batch are merged, these are sorted for
+    /// normalization, and only distinct values are kept for
+    /// `Normalization::Rank`.
+    Values([Vec<i32>; 3]),
+}
+
+impl Summary {
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (Summary::Range(a), Summary::Range(b)) => Summary::Range(a.union(b)),
+            (Summary::Values(mut a), Summary::Values(b)) => {
+                for (a, b) in a.iter_mut().zip(b.iter()) {
+                    a.extend(b);
+                }
+                Summary::Values(a)
+            }
+            _ => unreachable!(),
+        }
+    }
+    fn finish(mut self, instr: Instr) -> Self {
+        if let (Instr::Normalize(_), Summary::Values(values)) = (instr, &mut self) {
+            for values in values {
+                values.sort_unstable();
+                if let Instr::Normalize(Normalization::Rank) = instr {
+                    values.dedup();
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Map `n` linearly from the range `minimum` to `maximum` onto 0 to 255.
+fn stretch(n: i32, minimum: i32, maximum: i32) -> Result<i32, EvalError> {
+    // We use f64s instead of f32s to prevent this subtraction from overflowing.
+    let range = maximum as f64 - minimum as f64;
+    let relative =
+        if range == 0.0 { 0.5 }
+        else { (n as f64 - minimum as f64) / range };
+    if (0.0..=1.0).contains(&relative) {
+        Ok((255.0 * relative) as i32)
+    } else {
+        Err(EvalError::OutOfRange { value: n, minimum, maximum })
+    }
+}
+
+/// Map `n` onto 0 to 255 based on where it falls among `sorted`.
+fn normalize(kind: Normalization, n: i32, sorted: &[i32]) -> Result<i32, EvalError> {
+    let (minimum, maximum) = (sorted[0], sorted[sorted.len() - 1]);
+    if n < minimum || n > maximum {
+        return Err(EvalError::OutOfRange { value: n, minimum, maximum });
+    }
+    let last = sorted.len() - 1;
+    Ok(match kind {
+        Normalization::Percentile(p) => {
+            let skipped = p as usize * last / 100;
+            let (low, high) = (sorted[skipped], sorted[last - skipped]);
+            stretch(std::cmp::max(low, std::cmp::min(high, n)), low, high)?
+        }
+        Normalization::Equalize => {
+            let at_or_below = |n| sorted.partition_point(|&m| m <= n);
+            let lowest = at_or_below(minimum);
+            if lowest == sorted.len() {
+                127
+            } else {
+                (255 * (at_or_below(n) - lowest) / (sorted.len() - lowest)) as i32
+            }
+        }
+        Normalization::Rank => {
+            let rank = sorted.binary_search(&n).map_err(|_| EvalError::OutOfRange { value: n, minimum, maximum })?;
+            (255 * rank).checked_div(last).map_or(127, |n| n as i32)
+        }
+    })
+}
+
+/// The values of every stack slot for a batch of inputs, stored one column
+/// per slot so that each instruction is a tight loop over plain integers.
+struct Batch {
+    xs: Vec<i32>,
+    ys: Vec<i32>,
+    ts: Vec<i32>,
+    layout: Layout,
+    /// The index of the batch's first input among the inputs of every batch.
+    start: usize,
+    stack: Vec<Column>,
+    /// The values of the variables in scope, innermost last.
+    vars: Vec<Column>,
+    /// The pairs that the enclosing loop bodies are being applied to,
+    /// innermost last.
+    iterands: Vec<(Column, Column)>,
+}
+
+impl Batch {
+    fn len(&self) -> usize {
+        self.xs.len()
+    }
+    fn fill(&self, n: i32) -> Vec<i32> {
+        vec![n; self.len()]
+    }
+    fn centered(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        let (center_x, center_y) = self.layout.center;
+        self.xs.iter().zip(&self.ys).map(move |(&x, &y)| (x.wrapping_sub(center_x), y.wrapping_sub(center_y)))
+    }
+    fn pop(&mut self) -> Column {
+        self.stack.pop().unwrap()
+    }
+    fn pop_2(&mut self) -> (Column, Column) {
+        let a = self.pop();
+        let b = self.pop();
+        (b, a)
+    }
+    fn push(&mut self, column: Column) {
+        self.stack.push(column);
+    }
+    /// Find the range of values in each channel of the top stack slot.
+    fn channel_range(&self) -> ChannelRange {
+        let column = self.stack.last().unwrap();
+        let mut range = ChannelRange::EMPTY;
+        for ch in 0..3 {
+            for &n in column.channel(ch) {
+                range.minimums[ch] = std::cmp::min(range.minimums[ch], n);
+                range.maximums[ch] = std::cmp::max(range.maximums[ch], n);
+            }
+        }
+        range
+    }
+    /// Summarize the top stack slot for a barrier.
+    fn summarize(&self, instr: Instr) -> Summary {
+        let column = self.stack.last().unwrap();
+        match instr {
+            Instr::Scale256 => Summary::Range(self.channel_range()),
+            _ => Summary::Values([0, 1, 2].map(|ch| column.channel(ch).to_vec())),
+        }
+    }
+    /// Replace each value of the top stack slot using `f`, which is given the
+    /// channel that the value is in.
+    fn map_top(&mut self, f: impl Fn(usize, i32) -> Result<i32, EvalError>) -> Result<(), EvalError> {
+        let column = self.stack.last_mut().unwrap();
+        for (ch, ns) in column.channels_mut().iter_mut().enumerate() {
+            for n in ns.iter_mut() {
+                *n = f(ch, *n)?;
+            }
+        }
+        Ok(())
+    }
+    /// Evaluate a barrier given the summary of the values of every batch.
+    fn exec_barrier(&mut self, instr: Instr, summary: &Summary) -> Result<(), EvalError> {
+        match (instr, summary) {
+            (Instr::Scale256, Summary::Range(range)) =>
+                self.map_top(|ch, n| stretch(n, range.minimums[ch], range.maximums[ch])),
+            (Instr::Normalize(kind), Summary::Values(values)) =>
+                self.map_top(|ch, n| normalize(kind, n, &values[ch])),
+            (_, Summary::Values(values)) => {
+                let (layout, start) = (self.layout, self.start);
+                let column = self.stack.last_mut().unwrap();
+                for (ch, ns) in column.channels_mut().iter_mut().enumerate() {
+                    for (i, n) in ns.iter_mut().enumerate() {
+                        *n = layout.sample(instr, &values[ch], start + i);
+                    }
+                }
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+    }
+    fn exec(&mut self, instr: Instr) {
+        match instr {
+            Instr::Lit(n) => self.push(Column::Same(self.fill(n))),
+            Instr::Rgb([r, g, b]) => self.push(Column::Rgb([
+                self.fill(r as i32),
+                self.fill(g as i32),
+                self.fill(b as i32),
+            ])),
+            Instr::PixelX => self.push(Column::Same(self.xs.clone())),
+            Instr::PixelY => self.push(Column::Same(self.ys.clone())),
+            Instr::Time => self.push(Column::Same(self.ts.clone())),
+            Instr::CenteredX => self.push(Column::Same(self.centered().map(|(x, _)| x).collect())),
+            Instr::CenteredY => self.push(Column::Same(self.centered().map(|(_, y)| y).collect())),
+            Instr::Radius => self.push(Column::Same(self.centered()
+                .map(|(x, y)| (x as f64).hypot(y as f64) as i32)
+                .collect())),
+            Instr::Angle => self.push(Column::Same(self.centered()
+                .map(|(x, y)| ((y as f64).atan2(x as f64) * 128.0 / std::f64::consts::PI).round() as i32 & 255)
+                .collect())),
+            Instr::Channel => self.push(Column::Rgb([self.fill(-1), self.fill(0), self.fill(1)])),
+            Instr::Iterand => {
+                let (x, y) = self.iterands.last().unwrap().clone();
+                self.push(x);
+                self.push(y);
+            }
+            Instr::Scale256 | Instr::Normalize(_) | Instr::Offset(..) | Instr::Blur(_) | Instr::Sobel
+                | Instr::Iterate(..) => unreachable!(),
+            Instr::Unary(op) => {
+                let a = self.pop();
+                self.push(a.eval_unary(op));
+            }
+            Instr::Binary(op) => {
+                let (a, b) = self.pop_2();
+                self.push(a.eval_binary(&b, op));
+            }
+            Instr::Noise(seed, scale) => {
+                let (a, b) = self.pop_2();
+                self.push(a.zip(&b, |xs, ys| noise::eval_slices(seed, scale, xs, ys)));
+            }
+            Instr::Bind => {
+                let a = self.pop();
+                self.vars.push(a);
+            }
+            Instr::Unbind => {
+                self.vars.pop();
+            }
+            Instr::Load(n) => self.push(self.vars[self.vars.len() - 1 - n as usize].clone()),
+            Instr::Swap => {
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            }
+            Instr::UnaryPair(op) => {
+                let (n_1, n_2) = self.pop_2();
+                self.push(n_1.eval_unary(op));
+                self.push(n_2.eval_unary(op));
+            }
+            Instr::BinaryPair(op) => {
+                let (b_1, b_2) = self.pop_2();
+                let (a_1, a_2) = self.pop_2();
+                self.push(a_1.eval_binary(&b_1, op));
+                self.push(a_2.eval_binary(&b_2, op));
+            }
+            Instr::BinaryToPair(op_1, op_2) => {
+                let (a, b) = self.pop_2();
+                self.push(a.clone().eval_binary(&b, op_1));
+                self.push(a.eval_binary(&b, op_2));
+            }
+            Instr::UnaryComplex(op) => {
+                let (re, im) = self.pop_2();
+                let (re, im) = Column::map_pair(re, im, |res, ims| op.eval_slices(res, ims));
+                self.push(re);
+                self.push(im);
+            }
+            Instr::ComplexMul => {
+                let (re_b, im_b) = self.pop_2();
+                let (re_a, im_a) = self.pop_2();
+                let (re, im) = Column::zip_pair(re_a, im_a, &re_b, &im_b, complex_mul_slices);
+                self.push(re);
+                self.push(im);
+            }
+            Instr::Select => {
+                let (val_then, val_else) = self.pop_2();
+                let val_cond = self.pop();
+                self.push(Column::select(&val_cond, &val_then, &val_else));
+            }
+            Instr::SelectPair => {
+                let (else_1, else_2) = self.pop_2();
+                let (then_1, then_2) = self.pop_2();
+                let cond = self.pop();
+                self.push(Column::select(&cond, &then_1, &else_1));
+                self.push(Column::select(&cond, &then_2, &else_2));
+            }
+            Instr::SelectPairLanes => {
+                let (else_1, else_2) = self.pop_2();
+                let (then_1, then_2) = self.pop_2();
+                let (cond_1, cond_2) = self.pop_2();
+                self.push(Column::select(&cond_1, &then_1, &else_1));
+                self.push(Column::select(&cond_2, &then_2, &else_2));
+            }
+            Instr::LitF(n) => self.push(Column::Same(self.fill(n.to_bits() as i32))),
+            Instr::UnaryF(op) => {
+                let a = self.pop();
+                self.push(a.eval_funary(op));
+            }
+            Instr::BinaryF(op) => {
+                let (a, b) = self.pop_2();
+                self.push(a.eval_fbinary(&b, op));
+            }
+            Instr::IntToFloat => {
+                let a = self.pop();
+                self.push(a.int_to_float());
+            }
+            Instr::FloatToInt => {
+                let a = self.pop();
+                self.push(a.float_to_int());
+            }
+        }
+    }
+}
+
+impl Batch {
+    fn new(inputs: Vec<(i32, i32, i32)>, layout: Layout, start: usize) -> Self {
+        let mut batch = Self {
+            xs: Vec::new(),
+            ys: Vec::new(),
+            ts: Vec::new(),
+            layout,
+            start,
+            stack: Vec::new(),
+            vars: Vec::new(),
+            iterands: Vec::new(),
+        };
+        for (x, y, t) in inputs {
+            batch.xs.push(x);
+            batch.ys.push(y);
+            batch.ts.push(t);
+        }
+        batch
+    }
+    /// Run instructions starting from `pc` until the program ends or reaches a
+    /// barrier, which can't be evaluated without knowing the values across
+    /// every batch. If `pc` is itself at a barrier, `summary` is used to
+    /// evaluate it first. Returns where evaluation stopped, along with a
+    /// summary of the values it needs if it stopped at a barrier.
+    fn run(&mut self, instrs: &[Instr], mut pc: usize, summary: Option<&Summary>) -> Result<(usize, Option<Summary>), EvalError> {
+        if let Some(summary) = summary {
+            self.exec_barrier(instrs[pc], summary)?;
+            pc += 1;
+        }
+        while pc < instrs.len() {
+            if instrs[pc].is_barrier() {
+                return Ok((pc, Some(self.summarize(instrs[pc]))));
+            }
+            pc = self.step(instrs, pc);
+        }
+        Ok((pc, None))
+    }
+    /// Execute the instruction at `pc`, along with its loop body if it's an
+    /// `Iterate`, and return the position of the next instruction.
+    fn step(&mut self, instrs: &[Instr], pc: usize) -> usize {
+        match instrs[pc] {
+            Instr::Iterate(n, len) => {
+                let end = pc + 1 + len as usize;
+                self.iterate(n, &instrs[pc + 1..end]);
+                end
+            }
+            instr => {
+                self.exec(instr);
+                pc + 1
+            }
+        }
+    }
+    /// Apply a loop body, which can't contain barriers, to the top pair up to
+    /// `n` times. Entries whose pair has escaped stop changing, so every entry
+    /// goes through the same number of iterations.
+    fn iterate(&mut self, n: u8, body: &[Instr]) {
+        let (mut x, mut y) = self.pop_2();
+        let mut counts = Column::Same(self.fill(n as i32));
+        let escapes = |a: i32, b: i32| a.unsigned_abs() > ESCAPE_BOUND || b.unsigned_abs() > ESCAPE_BOUND;
+        for i in 0..n {
+            self.iterands.push((x.clone(), y.clone()));
+            let mut pc = 0;
+            while pc < body.len() {
+                pc = self.step(body, pc);
+            }
+            self.iterands.pop();
+            let (mut new_x, mut new_y) = self.pop_2();
+
+            // Work channel by channel if anything differs between channels.
+            if [&x, &y, &new_x, &new_y, &counts].iter().any(|column| matches!(column, Column::Rgb(_))) {
+                for column in [&mut x, &mut y, &mut new_x, &mut new_y, &mut counts] {
+                    let rgb = std::mem::replace(column, Column::Same(Vec::new())).into_rgb();
+                    *column = Column::Rgb(rgb);
+                }
+            }
+            let channels = x.channels_mut().iter_mut()
+                .zip(y.channels_mut())
+                .zip(new_x.channels_mut().iter().zip(new_y.channels_mut().iter()))
+                .zip(counts.channels_mut());
+            for (((xs, ys), (new_xs, new_ys)), counts) in channels {
+                for j in 0..counts.len() {
+                    if counts[j] != n as i32 {
+                        continue;
+                    }
+                    if escapes(new_xs[j], new_ys[j]) {
+                        counts[j] = i as i32;
+                    } else {
+                        xs[j] = new_xs[j];
+                        ys[j] = new_ys[j];
+                    }
+                }
+            }
+        }
+        self.push(counts);
+    }
+    fn into_colors(mut self) -> Vec<Color> {
+        assert_eq!(self.stack.len(), 1);
+        let result = self.pop();
+        (0..self.len()).map(|i| result.color(i)).collect()
+    }
+}
+
+impl Program {
+    /// Evaluate the program on several batches of inputs at once, spreading
+    /// them across up to `threads` threads. Normalizing and neighborhood
+    /// instructions work over all of the batches together, so the result
+    /// doesn't depend on how the inputs are split up.
+    pub fn eval_tiles(&self, tiles: Vec<Vec<(i32, i32, i32)>>, layout: Layout, threads: usize) -> Result<Vec<Vec<Color>>, EvalError> {
+        let mut start = 0;
+        let mut batches = tiles.into_iter()
+            .map(|tile| {
+                let len = tile.len();
+                start += len;
+                Batch::new(tile, layout, start - len)
+            })
+            .collect::<Vec<_>>();
+        let mut pc = 0;
+        let mut summary = None;
+        loop {
+            let results = utils::parallel_map(&mut batches, threads, |batch| {
+                batch.run(&self.instrs, pc, summary.as_ref())
+            }).into_iter().collect::<Result<Vec<_>, _>>()?;
+            pc = results.first().map_or(self.instrs.len(), |&(pc, _)| pc);
+            if pc == self.instrs.len() {
+                break;
+            }
+            summary = results.into_iter()
+                .filter_map(|(_, summary)| summary)
+                .reduce(Summary::merge)
+                .map(|summary| summary.finish(self.instrs[pc]));
+        }
+        Ok(batches.into_iter().map(Batch::into_colors).collect())
+    }
+}
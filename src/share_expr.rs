@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+
+use crate::arena::{ExprArena, IExprId, VExprId, FExprId, INode, VNode, FNode};
+use crate::expr::{IExpr, VExpr, FExpr};
+
+/// Subexpressions with fewer nodes than this are cheap enough to evaluate
+/// again that binding them would only make the expression harder to read.
+const MIN_SHARED_SIZE: usize = 3;
+
+struct NodeInfo {
+    /// The number of nodes that refer to this one.
+    uses: usize,
+    size: usize,
+    /// Whether the node is free of variables, and so means the same thing
+    /// wherever it appears.
+    closed: bool,
+}
+
+/// Counts how often each integer node of an interned arena is used.
+struct Counter<'a> {
+    arena: &'a ExprArena,
+    info: HashMap<IExprId, NodeInfo>,
+    /// Every integer node, each after all of its subexpressions.
+    order: Vec<IExprId>,
+}
+
+impl Counter<'_> {
+    /// Returns the size of the subexpression and whether it's closed.
+    fn iexpr(&mut self, id: IExprId) -> (usize, bool) {
+        use INode::*;
+        if let Some(info) = self.info.get_mut(&id) {
+            info.uses += 1;
+            return (info.size, info.closed);
+        }
+        let (size, closed) = match self.arena.inode(id) {
+            Lit(_) | Rgb(_) | PixelX | PixelY | Channel | Time | Radius | Angle => (0, true),
+            Var(_) => (0, false),
+            Scale256(sub_e) | UnaryI(_, sub_e) | Normalize(_, sub_e)
+                | Offset(_, _, sub_e) | Blur(_, sub_e) | Sobel(sub_e) => self.iexpr(sub_e),
+            BinaryI(_, e_1, e_2) => self.iexpr_2(e_1, e_2),
+            Let(e_1, e_2) => (self.iexpr_2(e_1, e_2).0, false),
+            BinaryV(_, sub_e) | Noise(_, _, sub_e) => self.vexpr(sub_e),
+            // The body refers to the loop's own iterand, so treat the whole
+            // loop as open rather than tracking which iterands are free.
+            Iterate(_, e_1, e_2) => (self.vexpr_2(e_1, e_2).0, false),
+            IfThenElseI(e_1, e_2, e_3) => {
+                let (size, closed) = self.iexpr_2(e_1, e_2);
+                let (size_3, closed_3) = self.iexpr(e_3);
+                (size + size_3, closed && closed_3)
+            }
+            IfThenElseV(e_1, e_2) => {
+                let (size_1, closed_1) = self.iexpr(e_1);
+                let (size_2, closed_2) = self.vexpr(e_2);
+                (size_1 + size_2, closed_1 && closed_2)
+            }
+            FromF(sub_e) => self.fexpr(sub_e),
+        };
+        self.info.insert(id, NodeInfo { uses: 1, size: size + 1, closed });
+        self.order.push(id);
+        (size + 1, closed)
+    }
+    fn iexpr_2(&mut self, e_1: IExprId, e_2: IExprId) -> (usize, bool) {
+        let (size_1, closed_1) = self.iexpr(e_1);
+        let (size_2, closed_2) = self.iexpr(e_2);
+        (size_1 + size_2, closed_1 && closed_2)
+    }
+    // Pair and float nodes can't be bound to variables, so they're visited
+    // again every time they're used.
+    fn vexpr(&mut self, id: VExprId) -> (usize, bool) {
+        use VNode::*;
+        let (size, closed) = match self.arena.vnode(id) {
+            Pixel | CenteredPixel => (0, true),
+            Iterand => (0, false),
+            Swap(sub_e) | UnaryV(_, sub_e) | UnaryC(_, sub_e) => self.vexpr(sub_e),
+            BinaryI(_, _, e_1, e_2) => self.iexpr_2(e_1, e_2),
+            BinaryV(_, e_1, e_2) | ComplexMul(e_1, e_2) => self.vexpr_2(e_1, e_2),
+            IfThenElseI(e_1, e_2, e_3) => {
+                let (size_1, closed_1) = self.iexpr(e_1);
+                let (size, closed) = self.vexpr_2(e_2, e_3);
+                (size_1 + size, closed_1 && closed)
+            }
+            IfThenElseV(e_1, e_2, e_3) => {
+                let (size, closed) = self.vexpr_2(e_1, e_2);
+                let (size_3, closed_3) = self.vexpr(e_3);
+                (size + size_3, closed && closed_3)
+            }
+        };
+        (size + 1, closed)
+    }
+    fn vexpr_2(&mut self, e_1: VExprId, e_2: VExprId) -> (usize, bool) {
+        let (size_1, closed_1) = self.vexpr(e_1);
+        let (size_2, closed_2) = self.vexpr(e_2);
+        (size_1 + size_2, closed_1 && closed_2)
+    }
+    fn fexpr(&mut self, id: FExprId) -> (usize, bool) {
+        use FNode::*;
+        let (size, closed) = match self.arena.fnode(id) {
+            Lit(_) => (0, true),
+            FromI(sub_e) => self.iexpr(sub_e),
+            UnaryF(_, sub_e) => self.fexpr(sub_e),
+            BinaryF(_, e_1, e_2) => {
+                let (size_1, closed_1) = self.fexpr(e_1);
+                let (size_2, closed_2) = self.fexpr(e_2);
+                (size_1 + size_2, closed_1 && closed_2)
+            }
+        };
+        (size + 1, closed)
+    }
+}
+
+/// Copies nodes out of an interned arena, replacing shared nodes with
+/// variables.
+///
+/// Each method takes the number of shared nodes whose bindings are in scope,
+/// and the number of `Let`s from the original expression between the node and
+/// those bindings.
+struct Builder<'a> {
+    arena: &'a ExprArena,
+    /// The position of each shared node in the list of bindings.
+    bindings: HashMap<IExprId, usize>,
+}
+
+impl Builder<'_> {
+    fn iexpr(&self, id: IExprId, scope: usize, depth: usize) -> IExpr {
+        match self.bindings.get(&id) {
+            Some(&binding) if binding < scope => IExpr::Var((depth + scope - 1 - binding) as u32),
+            _ => self.inode(id, scope, depth),
+        }
+    }
+    /// Copy a node without checking whether it's shared itself.
+    fn inode(&self, id: IExprId, scope: usize, depth: usize) -> IExpr {
+        use INode::*;
+        let i = |id| Box::new(self.iexpr(id, scope, depth));
+        let v = |id| Box::new(self.vexpr(id, scope, depth));
+        let f = |id| Box::new(self.fexpr(id, scope, depth));
+        match self.arena.inode(id) {
+            Lit(n) => IExpr::Lit(n),
+            Rgb(rgb) => IExpr::Rgb(rgb),
+            PixelX => IExpr::PixelX,
+            PixelY => IExpr::PixelY,
+            Channel => IExpr::Channel,
+            Time => IExpr::Time,
+            Radius => IExpr::Radius,
+            Angle => IExpr::Angle,
+            Var(n) => IExpr::Var(n),
+            Scale256(sub_e) => IExpr::Scale256(i(sub_e)),
+            UnaryI(op, sub_e) => IExpr::UnaryI(op, i(sub_e)),
+            BinaryI(op, e_1, e_2) => IExpr::BinaryI(op, i(e_1), i(e_2)),
+            BinaryV(op, sub_e) => IExpr::BinaryV(op, v(sub_e)),
+            IfThenElseI(e_1, e_2, e_3) => IExpr::IfThenElseI(i(e_1), i(e_2), i(e_3)),
+            IfThenElseV(e_1, e_2) => IExpr::IfThenElseV(i(e_1), v(e_2)),
+            FromF(sub_e) => IExpr::FromF(f(sub_e)),
+            Noise(seed, scale, sub_e) => IExpr::Noise(seed, scale, v(sub_e)),
+            Normalize(kind, sub_e) => IExpr::Normalize(kind, i(sub_e)),
+            Offset(dx, dy, sub_e) => IExpr::Offset(dx, dy, i(sub_e)),
+            Blur(radius, sub_e) => IExpr::Blur(radius, i(sub_e)),
+            Sobel(sub_e) => IExpr::Sobel(i(sub_e)),
+            Iterate(n, e_1, e_2) => IExpr::Iterate(n, v(e_1), v(e_2)),
+            Let(e_1, e_2) => IExpr::Let(i(e_1), Box::new(self.iexpr(e_2, scope, depth + 1))),
+        }
+    }
+    fn vexpr(&self, id: VExprId, scope: usize, depth: usize) -> VExpr {
+        use VNode::*;
+        let i = |id| Box::new(self.iexpr(id, scope, depth));
+        let v = |id| Box::new(self.vexpr(id, scope, depth));
+        match self.arena.vnode(id) {
+            Pixel => VExpr::Pixel,
+            CenteredPixel => VExpr::CenteredPixel,
+            Iterand => VExpr::Iterand,
+            Swap(sub_e) => VExpr::Swap(v(sub_e)),
+            BinaryI(op_1, op_2, e_1, e_2) => VExpr::BinaryI(op_1, op_2, i(e_1), i(e_2)),
+            UnaryV(op, sub_e) => VExpr::UnaryV(op, v(sub_e)),
+            BinaryV(op, e_1, e_2) => VExpr::BinaryV(op, v(e_1), v(e_2)),
+            IfThenElseI(e_1, e_2, e_3) => VExpr::IfThenElseI(i(e_1), v(e_2), v(e_3)),
+            IfThenElseV(e_1, e_2, e_3) => VExpr::IfThenElseV(v(e_1), v(e_2), v(e_3)),
+            UnaryC(op, sub_e) => VExpr::UnaryC(op, v(sub_e)),
+            ComplexMul(e_1, e_2) => VExpr::ComplexMul(v(e_1), v(e_2)),
+        }
+    }
+    fn fexpr(&self, id: FExprId, scope: usize, depth: usize) -> FExpr {
+        use FNode::*;
+        let i = |id| Box::new(self.iexpr(id, scope, depth));
+        let f = |id| Box::new(self.fexpr(id, scope, depth));
+        match self.arena.fnode(id) {
+            Lit(n) => FExpr::Lit(n),
+            FromI(sub_e) => FExpr::FromI(i(sub_e)),
+            UnaryF(op, sub_e) => FExpr::UnaryF(op, f(sub_e)),
+            BinaryF(op, e_1, e_2) => FExpr::BinaryF(op, f(e_1), f(e_2)),
+        }
+    }
+}
+
+impl IExpr {
+    /// Bind every subexpression that appears more than once to a variable, so
+    /// that it's only evaluated once. The bindings are placed around the whole
+    /// expression, each after the ones it depends on.
+    pub fn share_common_subexpressions(&self) -> IExpr {
+        let mut arena = ExprArena::interning();
+        let root = arena.insert_iexpr(self);
+
+        let mut counter = Counter { arena: &arena, info: HashMap::new(), order: Vec::new() };
+        counter.iexpr(root);
+        let shared = counter.order.iter()
+            .copied()
+            .filter(|id| {
+                let info = &counter.info[id];
+                info.uses > 1 && info.closed && info.size >= MIN_SHARED_SIZE
+            })
+            .collect::<Vec<_>>();
+
+        let builder = Builder {
+            arena: &arena,
+            bindings: shared.iter().enumerate().map(|(binding, &id)| (id, binding)).collect(),
+        };
+        let body = builder.iexpr(root, shared.len(), 0);
+        shared.iter().enumerate().rev().fold(body, |body, (binding, &id)| {
+            IExpr::Let(Box::new(builder.inode(id, binding, 0)), Box::new(body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::expr::{IExpr, Unary, Binary};
+    use crate::gen_expr::Parameters;
+    use crate::gen_png::assert_same_pixels;
+    use crate::validate::Limits;
+
+    fn assert_sharing_keeps_pixels(e: &IExpr) {
+        let shared = e.share_common_subexpressions();
+        assert!(shared.validate(&Limits::default()).is_ok(), "`{}` is invalid", shared);
+        assert_same_pixels(e, &shared);
+    }
+
+    #[test]
+    fn sharing_keeps_known_cases_the_same() {
+        let cases = [
+            "(& (+ (* x (+ y 3)) (* x (+ y 3))) 255)",
+            "(& (let ((a x)) (^ (+ a (* y (- y 1))) (- a (* y (- y 1))))) 255)",
+            "(^ (scale-256 (* x (+ y 3))) (scale-256 (* x (+ y 3))))",
+            "(iterate 8 [+ [c* z z] [[+ -] (* x (+ y 3)) (* x (+ y 3))]] [/4 xy])",
+            "(& (+ (iterate 4 [c* z z] [neg xy]) (iterate 4 [c* z z] [neg xy])) 255)",
+        ];
+        for case in &cases {
+            let e = case.parse::<IExpr>().unwrap();
+            assert!(e.validate(&Limits::default()).is_ok(), "`{}` is invalid", case);
+            assert_sharing_keeps_pixels(&e);
+        }
+    }
+
+    #[test]
+    fn sharing_keeps_generated_expressions_the_same() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let params = Parameters::default();
+        for _ in 0..200 {
+            let e = params.gen_expr(&mut rng, 6, 2);
+            e.validate(&Limits::default()).unwrap();
+            assert_sharing_keeps_pixels(&e);
+            // Make sure there's something to share.
+            let twice = IExpr::BinaryI(
+                Binary::BitXor,
+                Box::new(e.clone()),
+                Box::new(IExpr::UnaryI(Unary::Mod256, Box::new(e))),
+            );
+            assert_sharing_keeps_pixels(&twice);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::expr::{IExpr, VExpr, FExpr, Unary, Binary, ComplexUnary, Color};
+use crate::interval::{self, Domain};
+
+/// The value of an expression that is the same at every pixel.
+fn constant(e: &IExpr) -> Option<Color> {
+    match *e {
+        IExpr::Lit(n) => Some([n; 3]),
+        IExpr::Rgb([r, g, b]) => Some([r as i32, g as i32, b as i32]),
+        IExpr::Channel => Some([-1, 0, 1]),
+        _ => None,
+    }
+}
+
+/// Turn a constant back into an expression, if it can be written as a literal.
+fn from_constant(color: Color) -> Option<IExpr> {
+    let [r, g, b] = color;
+    if r == g && g == b {
+        Some(IExpr::Lit(r))
+    } else if color.iter().all(|n| (0..=255).contains(n)) {
+        Some(IExpr::Rgb([r as u8, g as u8, b as u8]))
+    } else {
+        None
+    }
+}
+
+fn is_constant(e: &IExpr, n: i32) -> bool {
+    constant(e) == Some([n; 3])
+}
+
+fn eval_binary(op: Binary, a: Color, b: Color) -> Color {
+    [op.eval(a[0], b[0]), op.eval(a[1], b[1]), op.eval(a[2], b[2])]
+}
+
+/// Whether a constant condition holds in every channel (`Some(true)`), in no
+/// channel (`Some(false)`), or only in some of them (`None`).
+fn condition(color: Color) -> Option<bool> {
+    if color.iter().all(|&c| c > 0) {
+        Some(true)
+    } else if color.iter().all(|&c| c <= 0) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Like `condition`, for a condition that may vary, judging by the range of
+/// values it can take wherever it's rendered.
+fn known_condition(e: &IExpr) -> Option<bool> {
+    interval::condition(&e.range(&Domain::ANY))
+}
+
+/// Whether every value of `e` is already between 0 and 255, wherever it's
+/// rendered.
+fn is_component(e: &IExpr) -> bool {
+    interval::is_color(&e.range(&Domain::ANY))
+}
+
+/// The value of a pair expression that is the same at every pixel.
+fn constant_pair(e: &VExpr) -> Option<(Color, Color)> {
+    match e {
+        VExpr::Swap(sub_e) => constant_pair(sub_e).map(|(a, b)| (b, a)),
+        VExpr::BinaryI(op_1, op_2, e_1, e_2) => {
+            let (a, b) = (constant(e_1)?, constant(e_2)?);
+            Some((eval_binary(*op_1, a, b), eval_binary(*op_2, a, b)))
+        }
+        VExpr::UnaryV(op, sub_e) => {
+            let (a, b) = constant_pair(sub_e)?;
+            Some((a.map(|n| op.eval(n)), b.map(|n| op.eval(n))))
+        }
+        VExpr::BinaryV(op, e_1, e_2) => {
+            let (a_1, a_2) = constant_pair(e_1)?;
+            let (b_1, b_2) = constant_pair(e_2)?;
+            Some((eval_binary(*op, a_1, b_1), eval_binary(*op, a_2, b_2)))
+        }
+        _ => None,
+    }
+}
+
+/// Extract one element of a pair as an integer expression, if the pair's
+/// structure allows it.
+fn lane(e: VExpr, first: bool) -> Result<IExpr, VExpr> {
+    match e {
+        VExpr::Pixel => Ok(if first { IExpr::PixelX } else { IExpr::PixelY }),
+        VExpr::Swap(sub_e) => lane(*sub_e, !first).map_err(|sub_e| VExpr::Swap(Box::new(sub_e))),
+        VExpr::BinaryI(op_1, op_2, e_1, e_2) =>
+            Ok(IExpr::BinaryI(if first { op_1 } else { op_2 }, e_1, e_2)),
+        e => Err(e),
+    }
+}
+
+/// What applying `outer` to the result of `inner` reduces to.
+enum Composed {
+    Identity,
+    Single(Unary),
+}
+
+fn compose(outer: Unary, inner: Unary) -> Option<Composed> {
+    use Unary::*;
+    match (outer, inner) {
+        (Neg, Neg) => Some(Composed::Identity),
+        (Abs, Abs) | (Abs, Neg) => Some(Composed::Single(Abs)),
+        (Mod256, Mod256) | (Clamp256, Mod256) => Some(Composed::Single(Mod256)),
+        (Clamp256, Clamp256) | (Mod256, Clamp256) => Some(Composed::Single(Clamp256)),
+        _ => None,
+    }
+}
+
+fn simplify_unary(op: Unary, e: IExpr) -> IExpr {
+    if let Some(folded) = constant(&e).and_then(|n| from_constant(n.map(|n| op.eval(n)))) {
+        return folded;
+    }
+    match (op, e) {
+        (Unary::DivBy(1), e) => e,
+        (Unary::ModBy(1), _) => IExpr::Lit(0),
+        // Normalized values are always in range, for example.
+        (Unary::Mod256, e) | (Unary::Clamp256, e) if is_component(&e) => e,
+        (op, IExpr::UnaryI(inner, sub_e)) => match compose(op, inner) {
+            Some(Composed::Identity) => *sub_e,
+            Some(Composed::Single(op)) => simplify_unary(op, *sub_e),
+            None => IExpr::UnaryI(op, Box::new(IExpr::UnaryI(inner, sub_e))),
+        },
+        (op, e) => IExpr::UnaryI(op, Box::new(e)),
+    }
+}
+
+fn simplify_binary(op: Binary, e_1: IExpr, e_2: IExpr) -> IExpr {
+    use Binary::*;
+    if let (Some(a), Some(b)) = (constant(&e_1), constant(&e_2)) {
+        if let Some(folded) = from_constant(eval_binary(op, a, b)) {
+            return folded;
+        }
+    }
+    match op {
+        Add | BitOr | BitXor if is_constant(&e_1, 0) => e_2,
+        Add | Sub | BitOr | BitXor | Shl | Shr | RotL if is_constant(&e_2, 0) => e_1,
+        Div | Mod if is_constant(&e_2, 0) => IExpr::Lit(0),
+        Div if is_constant(&e_2, 1) => e_1,
+        Mod if is_constant(&e_2, 1) => IExpr::Lit(0),
+        Mul | BitAnd if is_constant(&e_1, 0) || is_constant(&e_2, 0) => IExpr::Lit(0),
+        Mul if is_constant(&e_1, 1) => e_2,
+        Mul if is_constant(&e_2, 1) => e_1,
+        BitAnd if is_constant(&e_1, -1) => e_2,
+        BitAnd if is_constant(&e_2, -1) => e_1,
+        BitOr if is_constant(&e_1, -1) || is_constant(&e_2, -1) => IExpr::Lit(-1),
+        _ => IExpr::BinaryI(op, Box::new(e_1), Box::new(e_2)),
+    }
+}
+
+impl IExpr {
+    /// Fold constant subexpressions and remove operations that can't affect
+    /// the result. The simplified expression renders the same image.
+    pub fn simplify(self) -> IExpr {
+        use IExpr::*;
+        match self {
+            Scale256(sub_e) => match sub_e.simplify() {
+                // Every channel has a range of 0, so every value maps to the middle.
+                sub_e if constant(&sub_e).is_some() => Lit(127),
+                sub_e @ Scale256(_) => sub_e,
+                sub_e => Scale256(Box::new(sub_e)),
+            },
+            UnaryI(op, sub_e) => simplify_unary(op, sub_e.simplify()),
+            BinaryI(op, e_1, e_2) => simplify_binary(op, e_1.simplify(), e_2.simplify()),
+            BinaryV(op, sub_e) => {
+                let sub_e = sub_e.simplify();
+                constant_pair(&sub_e)
+                    .and_then(|(a, b)| from_constant(eval_binary(op, a, b)))
+                    .unwrap_or_else(|| BinaryV(op, Box::new(sub_e)))
+            }
+            IfThenElseI(e_cond, e_then, e_else) => {
+                let e_cond = e_cond.simplify();
+                match known_condition(&e_cond) {
+                    Some(true) => e_then.simplify(),
+                    Some(false) => e_else.simplify(),
+                    None => IfThenElseI(
+                        Box::new(e_cond),
+                        Box::new(e_then.simplify()),
+                        Box::new(e_else.simplify())),
+                }
+            }
+            IfThenElseV(e_cond, e_case) => {
+                let e_cond = e_cond.simplify();
+                let e_case = e_case.simplify();
+                match known_condition(&e_cond) {
+                    Some(first) => lane(e_case, first)
+                        .unwrap_or_else(|e_case| IfThenElseV(Box::new(e_cond), Box::new(e_case))),
+                    None => IfThenElseV(Box::new(e_cond), Box::new(e_case)),
+                }
+            }
+            Noise(seed, scale, sub_e) => Noise(seed, scale, Box::new(sub_e.simplify())),
+            Normalize(kind, sub_e) => match sub_e.simplify() {
+                sub_e if constant(&sub_e).is_some() => Lit(127),
+                sub_e => Normalize(kind, Box::new(sub_e)),
+            },
+            // Every pixel of a constant has the same neighbors.
+            Offset(dx, dy, sub_e) => match sub_e.simplify() {
+                sub_e if constant(&sub_e).is_some() || (dx, dy) == (0, 0) => sub_e,
+                sub_e => Offset(dx, dy, Box::new(sub_e)),
+            },
+            Blur(radius, sub_e) => match sub_e.simplify() {
+                sub_e if constant(&sub_e).is_some() || radius == 0 => sub_e,
+                sub_e => Blur(radius, Box::new(sub_e)),
+            },
+            Sobel(sub_e) => match sub_e.simplify() {
+                sub_e if constant(&sub_e).is_some() => Lit(0),
+                sub_e => Sobel(Box::new(sub_e)),
+            },
+            Iterate(0, _, _) => Lit(0),
+            Iterate(n, e_body, e_init) => Iterate(n, Box::new(e_body.simplify()), Box::new(e_init.simplify())),
+            Let(e_value, e_body) => Let(Box::new(e_value.simplify()), Box::new(e_body.simplify())),
+            FromF(sub_e) => match sub_e.simplify() {
+                FExpr::Lit(n) => Lit(n as i32),
+                sub_e => FromF(Box::new(sub_e)),
+            },
+            leaf => leaf,
+        }
+    }
+}
+
+fn simplify_unary_v(op: Unary, e: VExpr) -> VExpr {
+    match (op, e) {
+        (Unary::DivBy(1), e) => e,
+        (op, VExpr::UnaryV(inner, sub_e)) => match compose(op, inner) {
+            Some(Composed::Identity) => *sub_e,
+            Some(Composed::Single(op)) => simplify_unary_v(op, *sub_e),
+            None => VExpr::UnaryV(op, Box::new(VExpr::UnaryV(inner, sub_e))),
+        },
+        (op, e) => VExpr::UnaryV(op, Box::new(e)),
+    }
+}
+
+impl VExpr {
+    /// Fold constant conditions and remove operations that can't affect the
+    /// result.
+    pub fn simplify(self) -> VExpr {
+        use VExpr::*;
+        match self {
+            Pixel => Pixel,
+            CenteredPixel => CenteredPixel,
+            Iterand => Iterand,
+            Swap(sub_e) => match sub_e.simplify() {
+                Swap(sub_e) => *sub_e,
+                sub_e => Swap(Box::new(sub_e)),
+            },
+            BinaryI(op_1, op_2, e_1, e_2) =>
+                BinaryI(op_1, op_2, Box::new(e_1.simplify()), Box::new(e_2.simplify())),
+            UnaryV(op, sub_e) => simplify_unary_v(op, sub_e.simplify()),
+            BinaryV(op, e_1, e_2) => BinaryV(op, Box::new(e_1.simplify()), Box::new(e_2.simplify())),
+            UnaryC(op, sub_e) => match (op, sub_e.simplify()) {
+                (ComplexUnary::Conj, UnaryC(ComplexUnary::Conj, sub_e)) => *sub_e,
+                (op, sub_e) => UnaryC(op, Box::new(sub_e)),
+            },
+            ComplexMul(e_1, e_2) => ComplexMul(Box::new(e_1.simplify()), Box::new(e_2.simplify())),
+            IfThenElseI(e_cond, e_then, e_else) => {
+                let e_cond = e_cond.simplify();
+                match known_condition(&e_cond) {
+                    Some(true) => e_then.simplify(),
+                    Some(false) => e_else.simplify(),
+                    None => IfThenElseI(
+                        Box::new(e_cond),
+                        Box::new(e_then.simplify()),
+                        Box::new(e_else.simplify())),
+                }
+            }
+            IfThenElseV(e_cond, e_then, e_else) => {
+                let e_cond = e_cond.simplify();
+                let both = constant_pair(&e_cond)
+                    .and_then(|(a, b)| Some((condition(a)?, condition(b)?)));
+                match both {
+                    Some((true, true)) => e_then.simplify(),
+                    Some((false, false)) => e_else.simplify(),
+                    _ => IfThenElseV(
+                        Box::new(e_cond),
+                        Box::new(e_then.simplify()),
+                        Box::new(e_else.simplify())),
+                }
+            }
+        }
+    }
+}
+
+impl FExpr {
+    /// Fold constant subexpressions. Only operations whose result can be
+    /// written as a single literal are folded.
+    pub fn simplify(self) -> FExpr {
+        use FExpr::*;
+        match self {
+            Lit(n) => Lit(n),
+            FromI(sub_e) => {
+                let sub_e = sub_e.simplify();
+                match constant(&sub_e) {
+                    Some([r, g, b]) if r == g && g == b => Lit(r as f32),
+                    _ => FromI(Box::new(sub_e)),
+                }
+            }
+            UnaryF(op, sub_e) => match sub_e.simplify() {
+                Lit(n) => Lit(op.eval(n)),
+                sub_e => UnaryF(op, Box::new(sub_e)),
+            },
+            BinaryF(op, e_1, e_2) => match (e_1.simplify(), e_2.simplify()) {
+                (Lit(a), Lit(b)) => Lit(op.eval(a, b)),
+                (e_1, e_2) => BinaryF(op, Box::new(e_1), Box::new(e_2)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::expr::IExpr;
+    use crate::gen_expr::Parameters;
+    use crate::gen_png::assert_same_pixels;
+    use crate::validate::Limits;
+
+    #[test]
+    fn simplifying_keeps_known_cases_the_same() {
+        let cases = [
+            "(scale-256 (scale-256 (* x y)))",
+            "(iterate 0 [c* z z] xy)",
+            "(mod-256 (rank (^ x y)))",
+            "(clamp (scale-256 (cube x)))",
+            "(? (< 3 2) (& x 255) (| (& y 255) 7))",
+            "(& (- (* x 3) (* x 3)) 255)",
+        ];
+        for case in &cases {
+            let e = case.parse::<IExpr>().unwrap();
+            assert!(e.validate(&Limits::default()).is_ok(), "`{}` is invalid", case);
+            assert_same_pixels(&e, &e.clone().simplify());
+        }
+    }
+
+    #[test]
+    fn simplifying_keeps_generated_expressions_the_same() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let params = Parameters::default();
+        for _ in 0..200 {
+            let e = params.gen_expr(&mut rng, 6, 2);
+            e.validate(&Limits::default()).unwrap();
+            assert_same_pixels(&e, &e.clone().simplify());
+        }
+    }
+}
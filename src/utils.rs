@@ -9,13 +9,13 @@ pub fn mutate<R: Rng>(rng: &mut R, p1: &[f32], p2: &[f32], out: &mut [f32]) {
     assert!(p1.len() == p2.len());
     assert!(p1.len() == out.len());
 
-    for i in 0..p1.len() {
-        out[i] = if rng.gen() { p1 } else { p2 }[i];
+    for (i, out) in out.iter_mut().enumerate() {
+        *out = if rng.gen() { p1 } else { p2 }[i];
     }
 }
 
 /// Choose an integer `n` from the range 0..weights.len() with probability
-/// proportional to `weights[n]`.
+/// proportional to `weights[n]`. Choices with no weight are never made.
 pub fn weighted_choice<R: Rng>(rng: &mut R, weights: &[f32]) -> usize {
     let sum = weights.iter().sum::<f32>();
     let val = sum * rand_unit(rng);
@@ -24,7 +24,7 @@ pub fn weighted_choice<R: Rng>(rng: &mut R, weights: &[f32]) -> usize {
     while { prev_sum += weights[i]; prev_sum < val } {
         i += 1;
     }
-    return i;
+    i
 }
 
 /// Return a small positive number. This can be anywhere from 0 to 45 but in practice
@@ -38,7 +38,7 @@ pub fn small_positive<R: Rng>(rng: &mut R) -> u8 {
     if sum == 0 {
         1
     } else {
-        sum.abs() as u8
+        sum.unsigned_abs()
     }
 }
 
@@ -61,18 +61,38 @@ pub fn clamp(min: i32, max: i32, n: i32) -> i32 {
     std::cmp::min(max, std::cmp::max(min, n))
 }
 
-pub fn array_zip_3<T>(
-    mut f: impl FnMut(T, T, T) -> T,
-    t_1: [T; 3],
-    t_2: [T; 3],
-    t_3: [T; 3],
-) -> [T; 3] {
-    let [t_1_0, t_1_1, t_1_2] = t_1;
-    let [t_2_0, t_2_1, t_2_2] = t_2;
-    let [t_3_0, t_3_1, t_3_2] = t_3;
-    [
-        f(t_1_0, t_2_0, t_3_0),
-        f(t_1_1, t_2_1, t_3_1),
-        f(t_1_2, t_2_2, t_3_2),
-    ]
+/// Apply `f` to every element of `items`, splitting the work between up to
+/// `threads` threads, and collect the results in order.
+pub fn parallel_map<T, R, F>(items: &mut [T], threads: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(&mut T) -> R + Sync,
+{
+    if threads <= 1 {
+        return items.iter_mut().map(f).collect();
+    }
+    let chunk_size = std::cmp::max(1, items.len().div_ceil(threads));
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles = items.chunks_mut(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter_mut().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+/// Escape the characters of `text` that have a special meaning in HTML.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
 }
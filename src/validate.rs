@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use crate::expr::{IExpr, VExpr, FExpr, Normalization, Unary};
+use crate::interval::{self, Domain};
+
+/// Bounds on the size of expressions that will be rendered. Generated
+/// expressions stay well within these.
+pub struct Limits {
+    pub max_depth: usize,
+    /// Nodes in loop bodies count once for each iteration and blurs count
+    /// once for each pixel they average, so this also bounds how long
+    /// evaluation takes.
+    pub max_nodes: usize,
+    pub max_blur_radius: u8,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: 32,
+            max_nodes: 2048,
+            max_blur_radius: 8,
+        }
+    }
+}
+
+/// A reason an expression can't safely be evaluated.
+#[derive(Debug)]
+pub enum ValidationError {
+    TooDeep { max_depth: usize },
+    TooManyNodes { max_nodes: usize },
+    ZeroDivisor,
+    /// The result isn't guaranteed to be a valid color component.
+    UnboundedOutput,
+    /// A `Var` doesn't refer to any enclosing `Let`, or an `Iterand` isn't in
+    /// a loop body.
+    UnboundVariable,
+    InvalidPercentile,
+    BlurTooWide { max_blur_radius: u8 },
+    /// Normalization and neighborhood nodes need the values of every pixel,
+    /// so they can't be evaluated separately on each iteration of a loop.
+    BarrierInLoop,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use ValidationError::*;
+        match self {
+            TooDeep { max_depth } =>
+                write!(f, "expression is nested more than {} levels deep", max_depth),
+            TooManyNodes { max_nodes } =>
+                write!(f, "expression has more than {} nodes, counting loop bodies once per iteration", max_nodes),
+            ZeroDivisor => write!(f, "expression divides by zero"),
+            UnboundedOutput => write!(f, "expression can produce values outside of 0 to 255"),
+            UnboundVariable => write!(f, "expression refers to a variable that isn't bound"),
+            InvalidPercentile => write!(f, "percentiles must be less than 50"),
+            BlurTooWide { max_blur_radius } =>
+                write!(f, "expression blurs with a radius of more than {}", max_blur_radius),
+            BarrierInLoop => write!(f, "expression normalizes or reads neighboring pixels inside a loop"),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+struct Validator<'a> {
+    limits: &'a Limits,
+    nodes: usize,
+    /// The number of `Let`s around the current node.
+    bound: usize,
+    /// The number of loop bodies around the current node.
+    loops: usize,
+    /// The number of times the current node is evaluated for each pixel.
+    weight: usize,
+}
+
+impl Validator<'_> {
+    fn enter(&mut self, depth: usize) -> Result<(), ValidationError> {
+        if depth > self.limits.max_depth {
+            Err(ValidationError::TooDeep { max_depth: self.limits.max_depth })
+        } else {
+            self.count(1)
+        }
+    }
+    /// Count `n` nodes' worth of work at the current node.
+    fn count(&mut self, n: usize) -> Result<(), ValidationError> {
+        self.nodes = self.nodes.saturating_add(n.saturating_mul(self.weight));
+        if self.nodes > self.limits.max_nodes {
+            Err(ValidationError::TooManyNodes { max_nodes: self.limits.max_nodes })
+        } else {
+            Ok(())
+        }
+    }
+    fn unary(&self, op: Unary) -> Result<(), ValidationError> {
+        match op {
+            Unary::DivBy(0) | Unary::ModBy(0) => Err(ValidationError::ZeroDivisor),
+            _ => Ok(()),
+        }
+    }
+    fn iexpr(&mut self, e: &IExpr, depth: usize) -> Result<(), ValidationError> {
+        use IExpr::*;
+        self.enter(depth)?;
+        let depth = depth + 1;
+        match e {
+            Lit(_) | Rgb(_) | PixelX | PixelY | Channel | Time | Radius | Angle => Ok(()),
+            Scale256(_) | Normalize(..) | Offset(..) | Blur(..) | Sobel(_) if self.loops > 0 =>
+                Err(ValidationError::BarrierInLoop),
+            Normalize(Normalization::Percentile(p), _) if *p >= 50 => Err(ValidationError::InvalidPercentile),
+            Blur(radius, _) if *radius > self.limits.max_blur_radius =>
+                Err(ValidationError::BlurTooWide { max_blur_radius: self.limits.max_blur_radius }),
+            Blur(radius, sub_e) => {
+                self.count((2 * *radius as usize + 1).pow(2))?;
+                self.iexpr(sub_e, depth)
+            }
+            Scale256(sub_e) | Normalize(_, sub_e) | Offset(_, _, sub_e) | Sobel(sub_e) =>
+                self.iexpr(sub_e, depth),
+            UnaryI(op, sub_e) => {
+                self.unary(*op)?;
+                self.iexpr(sub_e, depth)
+            }
+            BinaryI(_, e_1, e_2) => {
+                self.iexpr(e_1, depth)?;
+                self.iexpr(e_2, depth)
+            }
+            BinaryV(_, sub_e) => self.vexpr(sub_e, depth),
+            Var(n) if *n as usize >= self.bound => Err(ValidationError::UnboundVariable),
+            Var(_) => Ok(()),
+            Let(e_value, e_body) => {
+                self.iexpr(e_value, depth)?;
+                self.bound += 1;
+                let result = self.iexpr(e_body, depth);
+                self.bound -= 1;
+                result
+            }
+            Noise(_, 0, _) => Err(ValidationError::ZeroDivisor),
+            Noise(_, _, sub_e) => self.vexpr(sub_e, depth),
+            IfThenElseI(e_1, e_2, e_3) => {
+                self.iexpr(e_1, depth)?;
+                self.iexpr(e_2, depth)?;
+                self.iexpr(e_3, depth)
+            }
+            IfThenElseV(e_1, e_2) => {
+                self.iexpr(e_1, depth)?;
+                self.vexpr(e_2, depth)
+            }
+            FromF(sub_e) => self.fexpr(sub_e, depth),
+            Iterate(n, e_body, e_init) => {
+                self.vexpr(e_init, depth)?;
+                let weight = self.weight;
+                self.weight = weight.saturating_mul(*n as usize);
+                self.loops += 1;
+                let result = self.vexpr(e_body, depth);
+                self.loops -= 1;
+                self.weight = weight;
+                result
+            }
+        }
+    }
+    fn vexpr(&mut self, e: &VExpr, depth: usize) -> Result<(), ValidationError> {
+        use VExpr::*;
+        self.enter(depth)?;
+        let depth = depth + 1;
+        match e {
+            Pixel | CenteredPixel => Ok(()),
+            Iterand if self.loops == 0 => Err(ValidationError::UnboundVariable),
+            Iterand => Ok(()),
+            Swap(sub_e) | UnaryC(_, sub_e) => self.vexpr(sub_e, depth),
+            BinaryI(_, _, e_1, e_2) => {
+                self.iexpr(e_1, depth)?;
+                self.iexpr(e_2, depth)
+            }
+            UnaryV(op, sub_e) => {
+                self.unary(*op)?;
+                self.vexpr(sub_e, depth)
+            }
+            BinaryV(_, e_1, e_2) | ComplexMul(e_1, e_2) => {
+                self.vexpr(e_1, depth)?;
+                self.vexpr(e_2, depth)
+            }
+            IfThenElseI(e_1, e_2, e_3) => {
+                self.iexpr(e_1, depth)?;
+                self.vexpr(e_2, depth)?;
+                self.vexpr(e_3, depth)
+            }
+            IfThenElseV(e_1, e_2, e_3) => {
+                self.vexpr(e_1, depth)?;
+                self.vexpr(e_2, depth)?;
+                self.vexpr(e_3, depth)
+            }
+        }
+    }
+    fn fexpr(&mut self, e: &FExpr, depth: usize) -> Result<(), ValidationError> {
+        use FExpr::*;
+        self.enter(depth)?;
+        let depth = depth + 1;
+        match e {
+            Lit(_) => Ok(()),
+            FromI(sub_e) => self.iexpr(sub_e, depth),
+            UnaryF(_, sub_e) => self.fexpr(sub_e, depth),
+            BinaryF(_, e_1, e_2) => {
+                self.fexpr(e_1, depth)?;
+                self.fexpr(e_2, depth)
+            }
+        }
+    }
+}
+
+impl IExpr {
+    /// Check that the expression can be rendered without panicking or taking
+    /// an unreasonable amount of time. This should be called on any expression
+    /// that comes from outside the program before it's evaluated.
+    pub fn validate(&self, limits: &Limits) -> Result<(), ValidationError> {
+        Validator { limits, nodes: 0, bound: 0, loops: 0, weight: 1 }.iexpr(self, 1)?;
+        if interval::is_color(&self.range(&Domain::ANY)) {
+            Ok(())
+        } else {
+            Err(ValidationError::UnboundedOutput)
+        }
+    }
+}
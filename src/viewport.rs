@@ -0,0 +1,76 @@
+/// Maps the pixels of a rendered image to the coordinates that the expression
+/// is evaluated at.
+#[derive(Clone, Copy, Debug)]
+pub struct Viewport {
+    /// The coordinates at the top-left corner of the image.
+    pub origin: (f64, f64),
+    /// The distance covered by one pixel along each axis.
+    pub scale: (f64, f64),
+    /// The size of the image in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// The point that polar coordinates are measured from. This stays put
+    /// when the viewport is moved or resized, so that it's part of the
+    /// picture rather than of the view.
+    pub center: (i32, i32),
+}
+
+impl Viewport {
+    /// Show coordinates `0..width` by `0..height` with one pixel each.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            origin: (0.0, 0.0),
+            scale: (1.0, 1.0),
+            width,
+            height,
+            center: (width as i32 / 2, height as i32 / 2),
+        }
+    }
+
+    /// Show the same region at a different size.
+    pub fn resize(self, width: u32, height: u32) -> Self {
+        Self {
+            scale: (
+                self.scale.0 * self.width as f64 / width as f64,
+                self.scale.1 * self.height as f64 / height as f64,
+            ),
+            width,
+            height,
+            ..self
+        }
+    }
+
+    /// Magnify the view by `factor` around its middle.
+    pub fn zoom(self, factor: f64) -> Self {
+        let middle = self.middle();
+        Self { scale: (self.scale.0 / factor, self.scale.1 / factor), ..self }.look_at(middle)
+    }
+
+    /// Move the view so that `point` is in its middle.
+    pub fn look_at(self, point: (f64, f64)) -> Self {
+        Self {
+            origin: (
+                point.0 - self.scale.0 * self.width as f64 / 2.0,
+                point.1 - self.scale.1 * self.height as f64 / 2.0,
+            ),
+            ..self
+        }
+    }
+
+    /// The coordinates in the middle of the view.
+    pub fn middle(&self) -> (f64, f64) {
+        (
+            self.origin.0 + self.scale.0 * self.width as f64 / 2.0,
+            self.origin.1 + self.scale.1 * self.height as f64 / 2.0,
+        )
+    }
+
+    /// The coordinates that the pixel at column `x` and row `y` is evaluated
+    /// at: the ones whose unit square contains the middle of the pixel.
+    pub fn coords(&self, x: u32, y: u32) -> (i32, i32) {
+        (
+            (self.origin.0 + self.scale.0 * (x as f64 + 0.5)).floor() as i32,
+            (self.origin.1 + self.scale.1 * (y as f64 + 0.5)).floor() as i32,
+        )
+    }
+}